@@ -0,0 +1,876 @@
+use crate::elf;
+use crate::error::{self, RexError};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{self, File, Permissions};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt, symlink};
+use std::path::{Component, Path};
+use std::time::{Duration, UNIX_EPOCH};
+
+const BLOCK: usize = 512;
+const MAX_OCTAL_SIZE: u64 = 0o77777777777;
+const MAX_OCTAL_ID: u32 = 0o7777777;
+
+/// How a `Writer` stores what a plain ustar header cannot hold: long names
+/// and link targets, files of 8 GiB and more, large uids/gids
+/// (`--tar-format`). `Reader` understands all three.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TarFormat {
+    /// POSIX.1-1988 only; anything that does not fit is an error.
+    Ustar,
+    /// POSIX.1-2001 extended (`x`) headers, the most widely understood.
+    #[default]
+    Pax,
+    /// GNU `L`/`K` records and base-256 numbers.
+    Gnu,
+}
+
+impl TarFormat {
+    pub fn parse(value: &str) -> error::Result<Self> {
+        match value {
+            "ustar" => Ok(Self::Ustar),
+            "pax" => Ok(Self::Pax),
+            "gnu" => Ok(Self::Gnu),
+            _ => Err(RexError::Usage(format!(
+                "Unknown TAR format '{value}' (expected ustar, pax or gnu)"
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+    Hardlink,
+    Other,
+}
+
+#[derive(Debug)]
+pub struct Entry {
+    pub path: String,
+    pub kind: EntryKind,
+    pub mode: u32,
+    pub size: u64,
+    pub mtime: u64,
+    pub link: String,
+}
+
+/// Streaming TAR reader yielding one entry header at a time. Data of an entry
+/// that is neither unpacked nor read is skipped by the next `next_entry`.
+pub struct Reader<R: Read> {
+    inner: R,
+    pending: u64,
+}
+
+fn field_str(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+fn field_num(bytes: &[u8]) -> io::Result<u64> {
+    if bytes.first().is_some_and(|b| b & 0x80 != 0) {
+        let mut n = (bytes[0] & 0x7f) as u64;
+        for b in &bytes[1..] {
+            n = (n << 8) | *b as u64;
+        }
+        return Ok(n);
+    }
+    let text = field_str(bytes);
+    let text = text.trim_matches(|c: char| c == ' ' || c == '\0');
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, 8).map_err(|_| io::Error::other("Invalid TAR header number"))
+}
+
+fn padded(size: u64) -> u64 {
+    size.div_ceil(BLOCK as u64) * BLOCK as u64
+}
+
+fn pax_records(records: &[u8]) -> Vec<(String, String)> {
+    let text = String::from_utf8_lossy(records);
+    text.lines()
+        .filter_map(|l| l.split_once(' ').map(|(_, kv)| kv))
+        .filter_map(|kv| kv.split_once('='))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, pending: 0 }
+    }
+
+    fn skip_pending(&mut self) -> io::Result<()> {
+        io::copy(&mut (&mut self.inner).take(self.pending), &mut io::sink())?;
+        self.pending = 0;
+        Ok(())
+    }
+
+    fn read_long(&mut self, size: u64) -> io::Result<Vec<u8>> {
+        let mut data = vec![0u8; padded(size) as usize];
+        self.inner.read_exact(&mut data)?;
+        data.truncate(size as usize);
+        Ok(data)
+    }
+
+    pub fn next_entry(&mut self) -> io::Result<Option<Entry>> {
+        self.skip_pending()?;
+        let mut long_name = None;
+        let mut long_link = None;
+        let mut long_size = None;
+        let mut long_mtime = None;
+
+        loop {
+            let mut header = [0u8; BLOCK];
+            match self.inner.read_exact(&mut header) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e),
+            }
+            if header.iter().all(|b| *b == 0) {
+                return Ok(None);
+            }
+
+            let size = field_num(&header[124..136])?;
+            let kind = match header[156] {
+                b'L' => {
+                    long_name = Some(field_str(&self.read_long(size)?));
+                    continue;
+                }
+                b'K' => {
+                    long_link = Some(field_str(&self.read_long(size)?));
+                    continue;
+                }
+                b'x' => {
+                    for (key, value) in pax_records(&self.read_long(size)?) {
+                        match key.as_str() {
+                            "path" => long_name = Some(value),
+                            "linkpath" => long_link = Some(value),
+                            "size" => long_size = value.parse().ok(),
+                            "mtime" => {
+                                long_mtime = value.split('.').next().and_then(|s| s.parse().ok())
+                            }
+                            _ => {}
+                        }
+                    }
+                    continue;
+                }
+                b'g' => {
+                    self.read_long(size)?;
+                    continue;
+                }
+                b'0' | 0 | b'7' => EntryKind::File,
+                b'5' => EntryKind::Dir,
+                b'2' => EntryKind::Symlink,
+                b'1' => EntryKind::Hardlink,
+                _ => EntryKind::Other,
+            };
+
+            let path = long_name.unwrap_or_else(|| {
+                let name = field_str(&header[0..100]);
+                let prefix = match &header[257..263] {
+                    b"ustar\0" | b"ustar " => field_str(&header[345..500]),
+                    _ => String::new(),
+                };
+                if prefix.is_empty() {
+                    name
+                } else {
+                    format!("{prefix}/{name}")
+                }
+            });
+
+            let data_size = match kind {
+                EntryKind::File | EntryKind::Other => long_size.unwrap_or(size),
+                _ => 0,
+            };
+            self.pending = padded(data_size);
+
+            return Ok(Some(Entry {
+                path: path.trim_end_matches('/').to_string(),
+                kind,
+                mode: field_num(&header[100..108])? as u32 & 0o7777,
+                size: data_size,
+                mtime: match long_mtime {
+                    Some(mtime) => mtime,
+                    None => field_num(&header[136..148])?,
+                },
+                link: long_link.unwrap_or_else(|| field_str(&header[157..257])),
+            }));
+        }
+    }
+
+    /// Writes `entry` to `dest`. Hardlink targets are resolved against `root`.
+    pub fn unpack(&mut self, entry: &Entry, dest: &Path, root: &Path) -> io::Result<()> {
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Never write through a symlink an earlier entry left here.
+        if entry.kind != EntryKind::Other && dest.is_symlink() {
+            fs::remove_file(dest)?;
+        }
+        match entry.kind {
+            EntryKind::Dir => {
+                fs::create_dir_all(dest)?;
+                fs::set_permissions(dest, Permissions::from_mode(entry.mode | 0o700))?;
+            }
+            EntryKind::Symlink => {
+                let _ = fs::remove_file(dest);
+                symlink(&entry.link, dest)?;
+            }
+            EntryKind::Hardlink => {
+                let _ = fs::remove_file(dest);
+                fs::hard_link(root.join(&entry.link), dest)?;
+            }
+            EntryKind::File => {
+                let mut file = File::create(dest)?;
+                io::copy(&mut (&mut self.inner).take(entry.size), &mut file)?;
+                self.pending -= entry.size;
+                file.set_permissions(Permissions::from_mode(entry.mode))?;
+                set_mtime(&file, entry.mtime)?;
+            }
+            EntryKind::Other => {}
+        }
+        Ok(())
+    }
+}
+
+/// Fails unless `entry`, unpacked to `dest.join(&entry.path)` with hardlinks
+/// resolved against `dest`, stays below `dest`: absolute paths, `..`
+/// components and paths leading through a symlink (say `libs -> /etc`
+/// followed by `libs/passwd`) are refused.
+pub fn check_entry(entry: &Entry, dest: &Path) -> io::Result<()> {
+    let escapes = |path: &str| {
+        let path = Path::new(path);
+        if path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+            return true;
+        }
+        let mut dir = dest.to_path_buf();
+        let mut components = path.components().peekable();
+        while let Some(component) = components.next() {
+            if components.peek().is_none() {
+                break;
+            }
+            dir.push(component);
+            if dir
+                .symlink_metadata()
+                .is_ok_and(|m| m.file_type().is_symlink())
+            {
+                return true;
+            }
+        }
+        false
+    };
+    if escapes(&entry.path) || (entry.kind == EntryKind::Hardlink && escapes(&entry.link)) {
+        return Err(io::Error::other(format!(
+            "unsafe path in archive: {}",
+            entry.path
+        )));
+    }
+    Ok(())
+}
+
+/// Unpacks every entry of the TAR stream `inner` below `dest`, refusing
+/// any that would land outside it (see `check_entry`).
+pub fn unpack_all<R: Read>(inner: R, dest: &Path) -> io::Result<()> {
+    let mut reader = Reader::new(inner);
+    // Directory times are set last, as unpacking their contents changes them.
+    let mut dirs = vec![];
+    while let Some(entry) = reader.next_entry()? {
+        check_entry(&entry, dest)?;
+        reader.unpack(&entry, &dest.join(&entry.path), dest)?;
+        if entry.kind == EntryKind::Dir {
+            dirs.push((dest.join(&entry.path), entry.mtime));
+        }
+    }
+    for (dir, mtime) in dirs.into_iter().rev() {
+        set_mtime(&File::open(dir)?, mtime)?;
+    }
+    Ok(())
+}
+
+// A zero mtime (`--reproducible`) is left to the extraction time.
+fn set_mtime(file: &File, mtime: u64) -> io::Result<()> {
+    if mtime == 0 {
+        return Ok(());
+    }
+    file.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))
+}
+
+/// Ownership/permission overrides applied to every entry a `Writer` emits.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeaderOverrides {
+    pub owner: Option<(u32, u32)>,
+    pub mode: Option<u32>,
+    pub mtime: Option<u64>,
+}
+
+impl HeaderOverrides {
+    // Like chmod's `X`: directories and files that were executable also get
+    // the execute bits wherever the override grants read access.
+    pub fn mode(&self, mode: u32, executable: bool) -> u32 {
+        match self.mode {
+            Some(m) if executable => m | ((m & 0o444) >> 2),
+            Some(m) => m,
+            None => mode,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Header<'a> {
+    name: &'a [u8],
+    prefix: &'a [u8],
+    kind: u8,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    mtime: u64,
+    link: &'a [u8],
+}
+
+fn put_bytes(field: &mut [u8], value: &[u8]) {
+    let n = value.len().min(field.len());
+    field[..n].copy_from_slice(&value[..n]);
+}
+
+// Octal with a trailing NUL, or base-256 when the value does not fit.
+fn put_num(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    let text = format!("{value:0width$o}");
+    if text.len() <= width {
+        put_bytes(field, text.as_bytes());
+        return;
+    }
+    let len = field.len();
+    field[0] = 0x80;
+    for i in 1..len {
+        field[len - i] = value.checked_shr(8 * (i as u32 - 1)).unwrap_or(0) as u8;
+    }
+}
+
+// Pax record `<len> <key>=<value>\n`, where `<len>` counts itself too.
+fn pax_record(key: &str, value: &[u8]) -> Vec<u8> {
+    let base = key.len() + value.len() + 3;
+    let mut len = base + 1;
+    while len != base + len.to_string().len() {
+        len = base + len.to_string().len();
+    }
+    [format!("{len} {key}=").as_bytes(), value, b"\n"].concat()
+}
+
+// Splits a long name at a `/` into ustar's 155-byte prefix and 100-byte name.
+fn ustar_split(name: &[u8]) -> Option<(&[u8], &[u8])> {
+    if name.len() <= 100 {
+        return Some((b"", name));
+    }
+    let body = name.strip_suffix(b"/").unwrap_or(name);
+    body.iter()
+        .enumerate()
+        .filter(|(i, b)| **b == b'/' && *i <= 155)
+        .map(|(i, _)| (&name[..i], &name[i + 1..]))
+        .find(|(_, rest)| rest.len() <= 100)
+}
+
+impl Header<'_> {
+    fn encode(&self, format: TarFormat) -> [u8; BLOCK] {
+        let mut h = [0u8; BLOCK];
+        put_bytes(&mut h[0..100], self.name);
+        put_num(&mut h[100..108], self.mode as u64);
+        put_num(&mut h[108..116], self.uid as u64);
+        put_num(&mut h[116..124], self.gid as u64);
+        put_num(&mut h[124..136], self.size);
+        put_num(&mut h[136..148], self.mtime);
+        h[148..156].fill(b' ');
+        h[156] = self.kind;
+        put_bytes(&mut h[157..257], self.link);
+        if format == TarFormat::Gnu {
+            h[257..265].copy_from_slice(b"ustar  \0");
+        } else {
+            h[257..263].copy_from_slice(b"ustar\0");
+            h[263..265].copy_from_slice(b"00");
+            put_bytes(&mut h[345..500], self.prefix);
+        }
+        let sum: u32 = h.iter().map(|b| *b as u32).sum();
+        put_bytes(&mut h[148..156], format!("{sum:06o}\0 ").as_bytes());
+        h
+    }
+}
+
+/// Streaming TAR writer; see `TarFormat` for what happens to names, link
+/// targets and numbers that do not fit a ustar header. Files sharing an
+/// inode are stored once, later names becoming hardlink entries to the first.
+pub struct Writer<W: Write> {
+    inner: W,
+    overrides: HeaderOverrides,
+    format: TarFormat,
+    links: HashMap<(u64, u64), Vec<u8>>,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(inner: W, overrides: HeaderOverrides, format: TarFormat) -> Self {
+        Self {
+            inner,
+            overrides,
+            format,
+            links: HashMap::new(),
+        }
+    }
+
+    fn pad(&mut self, size: u64) -> io::Result<()> {
+        let zeros = [0u8; BLOCK];
+        self.inner
+            .write_all(&zeros[..(padded(size) - size) as usize])
+    }
+
+    // A GNU `L`/`K` record or pax `x` header carrying `data` for the next
+    // entry.
+    fn write_extension(&mut self, name: &[u8], kind: u8, data: &[u8]) -> io::Result<()> {
+        let size = data.len() as u64;
+        let header = Header {
+            name,
+            prefix: b"",
+            kind,
+            mode: 0o644,
+            uid: 0,
+            gid: 0,
+            size,
+            mtime: 0,
+            link: b"",
+        };
+        self.inner.write_all(&header.encode(self.format))?;
+        self.inner.write_all(data)?;
+        self.pad(size)
+    }
+
+    fn write_header(&mut self, header: &Header) -> io::Result<()> {
+        let mut header = *header;
+        let ids_fit = header.uid <= MAX_OCTAL_ID && header.gid <= MAX_OCTAL_ID;
+        match self.format {
+            TarFormat::Gnu => {
+                for (kind, value) in [(b'L', header.name), (b'K', header.link)] {
+                    if value.len() > 100 {
+                        self.write_extension(b"././@LongLink", kind, &[value, b"\0"].concat())?;
+                    }
+                }
+            }
+            TarFormat::Pax => {
+                let mut records = vec![];
+                if header.name.len() > 100 {
+                    records.extend(pax_record("path", header.name));
+                }
+                if header.link.len() > 100 {
+                    records.extend(pax_record("linkpath", header.link));
+                }
+                if header.size > MAX_OCTAL_SIZE {
+                    records.extend(pax_record("size", header.size.to_string().as_bytes()));
+                }
+                if !ids_fit {
+                    records.extend(pax_record("uid", header.uid.to_string().as_bytes()));
+                    records.extend(pax_record("gid", header.gid.to_string().as_bytes()));
+                }
+                if !records.is_empty() {
+                    self.write_extension(b"././@PaxHeader", b'x', &records)?;
+                }
+            }
+            TarFormat::Ustar => {
+                let name = String::from_utf8_lossy(header.name);
+                let too_big = |what: &str| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "{name}: {what} does not fit the ustar format; use --tar-format pax"
+                        ),
+                    )
+                };
+                let (prefix, rest) = ustar_split(header.name).ok_or_else(|| too_big("path"))?;
+                if header.link.len() > 100 {
+                    return Err(too_big("link target"));
+                }
+                if header.size > MAX_OCTAL_SIZE {
+                    return Err(too_big("size"));
+                }
+                if !ids_fit {
+                    return Err(too_big("owner"));
+                }
+                header.prefix = prefix;
+                header.name = rest;
+            }
+        }
+        self.inner.write_all(&header.encode(self.format))
+    }
+
+    /// Appends `root` and everything below it, stored under `prefix`.
+    /// Entries are written in name order; sockets, FIFOs and devices are
+    /// skipped.
+    pub fn append_dir_all(&mut self, prefix: &str, root: &Path) -> io::Result<()> {
+        self.append_path(prefix.as_bytes(), root)
+    }
+
+    fn append_path(&mut self, name: &[u8], path: &Path) -> io::Result<()> {
+        let meta = fs::symlink_metadata(path)?;
+        let kind = meta.file_type();
+        let (uid, gid) = self.overrides.owner.unwrap_or((meta.uid(), meta.gid()));
+        let mode = if elf::is_loader(path) {
+            meta.mode() & 0o7777
+        } else {
+            self.overrides
+                .mode(meta.mode() & 0o7777, meta.mode() & 0o111 != 0)
+        };
+        let mut header = Header {
+            name,
+            prefix: b"",
+            kind: b'0',
+            mode,
+            uid,
+            gid,
+            size: 0,
+            mtime: self.overrides.mtime.unwrap_or(meta.mtime().max(0) as u64),
+            link: b"",
+        };
+
+        if kind.is_dir() {
+            let dir_name = [name, b"/"].concat();
+            header.name = &dir_name;
+            header.kind = b'5';
+            header.mode = self.overrides.mode(meta.mode() & 0o7777, true);
+            self.write_header(&header)?;
+
+            let mut entries = fs::read_dir(path)?.collect::<io::Result<Vec<_>>>()?;
+            entries.sort_by_key(|e| e.file_name());
+            for entry in entries {
+                let child = [&dir_name, entry.file_name().as_bytes()].concat();
+                self.append_path(&child, &entry.path())?;
+            }
+        } else if kind.is_symlink() {
+            let target = fs::read_link(path)?;
+            header.kind = b'2';
+            header.mode = 0o777;
+            header.link = OsStr::as_bytes(target.as_os_str());
+            self.write_header(&header)?;
+        } else if kind.is_file() {
+            let inode = (meta.dev(), meta.ino());
+            if let Some(first) = self.links.get(&inode).cloned() {
+                header.kind = b'1';
+                header.link = &first;
+                return self.write_header(&header);
+            }
+            if meta.nlink() > 1 {
+                self.links.insert(inode, name.to_vec());
+            }
+            header.size = meta.len();
+            self.write_header(&header)?;
+            let copied = io::copy(&mut File::open(path)?.take(meta.len()), &mut self.inner)?;
+            if copied != meta.len() {
+                return Err(io::Error::other(format!(
+                    "{} shrank while being archived",
+                    path.display()
+                )));
+            }
+            self.pad(meta.len())?;
+        }
+        Ok(())
+    }
+
+    /// Writes the end-of-archive marker and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(&[0u8; BLOCK * 2])?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::path::PathBuf;
+    use std::time::SystemTime;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("rex-tar-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn archive(root: &Path, format: TarFormat, overrides: HeaderOverrides) -> io::Result<Vec<u8>> {
+        let mut writer = Writer::new(vec![], overrides, format);
+        writer.append_dir_all("bundle", root)?;
+        writer.finish()
+    }
+
+    fn mtime(path: &Path) -> u64 {
+        fs::symlink_metadata(path).unwrap().mtime() as u64
+    }
+
+    #[test]
+    fn mtimes_survive_a_round_trip() {
+        let tmp = TempDir::new("mtime");
+        let src = tmp.0.join("src");
+        fs::create_dir_all(src.join("conf")).unwrap();
+        let file = File::create(src.join("conf/app.toml")).unwrap();
+        let known = 1_600_000_000;
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(known))
+            .unwrap();
+        File::open(src.join("conf"))
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(known + 60))
+            .unwrap();
+
+        let bytes = archive(&src, TarFormat::Pax, HeaderOverrides::default()).unwrap();
+        let dest = tmp.0.join("dest");
+        unpack_all(&bytes[..], &dest).unwrap();
+        assert_eq!(mtime(&dest.join("bundle/conf/app.toml")), known);
+        assert_eq!(mtime(&dest.join("bundle/conf")), known + 60);
+    }
+
+    #[test]
+    fn reproducible_mtimes_are_left_to_extraction() {
+        let tmp = TempDir::new("mtime-zero");
+        let src = tmp.0.join("src");
+        fs::create_dir_all(&src).unwrap();
+        File::create(src.join("data"))
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(1_600_000_000))
+            .unwrap();
+
+        let overrides = HeaderOverrides {
+            mtime: Some(0),
+            ..Default::default()
+        };
+        let bytes = archive(&src, TarFormat::Pax, overrides).unwrap();
+        let mut reader = Reader::new(&bytes[..]);
+        while let Some(entry) = reader.next_entry().unwrap() {
+            assert_eq!(entry.mtime, 0, "{}", entry.path);
+        }
+
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let dest = tmp.0.join("dest");
+        unpack_all(&bytes[..], &dest).unwrap();
+        assert!(mtime(&dest.join("bundle/data")) >= started);
+    }
+
+    // Writes raw (name, kind, link, data) entries, bypassing the filesystem
+    // walk, to build archives a `Writer` would never produce.
+    fn raw_archive(entries: &[(&str, u8, &str, &[u8])]) -> Vec<u8> {
+        let mut writer = Writer::new(vec![], HeaderOverrides::default(), TarFormat::Pax);
+        for (name, kind, link, data) in entries {
+            let header = Header {
+                name: name.as_bytes(),
+                prefix: b"",
+                kind: *kind,
+                mode: 0o644,
+                uid: 0,
+                gid: 0,
+                size: data.len() as u64,
+                mtime: 0,
+                link: link.as_bytes(),
+            };
+            writer.write_header(&header).unwrap();
+            writer.inner.write_all(data).unwrap();
+            writer.pad(data.len() as u64).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn entries_through_a_symlink_are_refused() {
+        let tmp = TempDir::new("escape");
+        let outside = tmp.0.join("outside");
+        fs::create_dir_all(&outside).unwrap();
+        let outside_str = outside.to_str().unwrap();
+        let dest = tmp.0.join("dest");
+
+        let bytes = raw_archive(&[
+            ("b/libs", b'2', outside_str, b""),
+            ("b/libs/passwd", b'0', "", b"pwned"),
+        ]);
+        let err = unpack_all(&bytes[..], &dest).unwrap_err();
+        assert!(err.to_string().contains("b/libs/passwd"), "{err}");
+        assert!(!outside.join("passwd").exists());
+
+        fs::write(outside.join("shadow"), "secret").unwrap();
+        let bytes = raw_archive(&[
+            ("c/libs", b'2', outside_str, b""),
+            ("c/copy", b'1', "c/libs/shadow", b""),
+        ]);
+        assert!(unpack_all(&bytes[..], &dest).is_err());
+        assert!(!dest.join("c/copy").exists());
+
+        for name in ["/etc/passwd", "b/../../passwd"] {
+            let bytes = raw_archive(&[(name, b'0', "", b"pwned")]);
+            assert!(unpack_all(&bytes[..], &dest).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn files_replace_symlinks_instead_of_writing_through_them() {
+        let tmp = TempDir::new("replace-link");
+        let target = tmp.0.join("target");
+        fs::write(&target, "original").unwrap();
+        let dest = tmp.0.join("dest");
+
+        let bytes = raw_archive(&[
+            ("b/conf", b'2', target.to_str().unwrap(), b""),
+            ("b/conf", b'0', "", b"bundled"),
+        ]);
+        unpack_all(&bytes[..], &dest).unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "original");
+        assert!(!dest.join("b/conf").is_symlink());
+        assert_eq!(fs::read_to_string(dest.join("b/conf")).unwrap(), "bundled");
+    }
+
+    fn entries(bytes: &[u8]) -> Vec<(String, EntryKind, String)> {
+        let mut reader = Reader::new(bytes);
+        let mut out = vec![];
+        while let Some(entry) = reader.next_entry().unwrap() {
+            out.push((entry.path, entry.kind, entry.link));
+        }
+        out
+    }
+
+    // A 200+ byte path in 60-byte components and a 150-byte link target.
+    fn long_tree(root: &Path) -> (String, String) {
+        let deep = ["a".repeat(60), "b".repeat(60), "c".repeat(60)].join("/");
+        fs::create_dir_all(root.join(&deep)).unwrap();
+        fs::write(root.join(&deep).join("file.txt"), "deep").unwrap();
+        let target = format!("{}/target", "t".repeat(143));
+        symlink(&target, root.join("link")).unwrap();
+        (format!("bundle/{deep}/file.txt"), target)
+    }
+
+    #[test]
+    fn long_names_round_trip_in_pax_and_gnu() {
+        for format in [TarFormat::Pax, TarFormat::Gnu] {
+            let tmp = TempDir::new(&format!("long-{format:?}"));
+            let src = tmp.0.join("src");
+            let (deep, target) = long_tree(&src);
+
+            let bytes = archive(&src, format, HeaderOverrides::default()).unwrap();
+            let listed = entries(&bytes);
+            assert!(
+                listed.contains(&(deep.clone(), EntryKind::File, String::new())),
+                "{format:?}: {listed:?}"
+            );
+            assert!(listed.contains(&("bundle/link".into(), EntryKind::Symlink, target.clone())));
+
+            let dest = tmp.0.join("dest");
+            unpack_all(&bytes[..], &dest).unwrap();
+            assert_eq!(fs::read_to_string(dest.join(&deep)).unwrap(), "deep");
+            assert_eq!(
+                fs::read_link(dest.join("bundle/link")).unwrap(),
+                Path::new(&target)
+            );
+        }
+    }
+
+    #[test]
+    fn ustar_uses_the_prefix_field_and_rejects_what_does_not_fit() {
+        let tmp = TempDir::new("ustar");
+        let src = tmp.0.join("src");
+        let name = format!("{}/{}", "p".repeat(90), "n".repeat(90));
+        fs::create_dir_all(src.join(&name)).unwrap();
+        let bytes = archive(&src, TarFormat::Ustar, HeaderOverrides::default()).unwrap();
+        assert!(entries(&bytes).contains(&(
+            format!("bundle/{name}"),
+            EntryKind::Dir,
+            String::new()
+        )));
+
+        long_tree(&tmp.0.join("long"));
+        let err = archive(
+            &tmp.0.join("long"),
+            TarFormat::Ustar,
+            HeaderOverrides::default(),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("use --tar-format pax"), "{err}");
+
+        let single = tmp.0.join("single");
+        fs::create_dir_all(single.join("x".repeat(120))).unwrap();
+        let err = archive(&single, TarFormat::Ustar, HeaderOverrides::default()).unwrap_err();
+        assert!(err.to_string().contains("path does not fit"), "{err}");
+    }
+
+    #[test]
+    fn ustar_split_picks_a_slash_that_fits_both_fields() {
+        let short = b"bundle/libs/libc.so.6";
+        assert_eq!(ustar_split(short), Some((&b""[..], &short[..])));
+
+        let name = format!("{}/{}", "p".repeat(120), "n".repeat(90)).into_bytes();
+        assert_eq!(ustar_split(&name), Some((&name[..120], &name[121..])));
+
+        // The name part may not exceed 100 bytes, the prefix 155.
+        let long_tail = format!("{}/{}", "p".repeat(20), "n".repeat(101)).into_bytes();
+        assert_eq!(ustar_split(&long_tail), None);
+        let long_head = format!("{}/{}", "p".repeat(156), "n".repeat(10)).into_bytes();
+        assert_eq!(ustar_split(&long_head), None);
+
+        // A trailing slash (directories) is not a split point.
+        let dir = format!("{}/", "d".repeat(101)).into_bytes();
+        assert_eq!(ustar_split(&dir), None);
+    }
+
+    #[test]
+    fn hardlinked_files_are_stored_once() {
+        let tmp = TempDir::new("hardlink");
+        let src = tmp.0.join("src");
+        fs::create_dir_all(src.join("libs")).unwrap();
+        fs::write(src.join("libs/libfoo.so.1"), vec![7u8; 64 * 1024]).unwrap();
+        fs::hard_link(src.join("libs/libfoo.so.1"), src.join("libs/libfoo.so")).unwrap();
+        let separate = tmp.0.join("separate");
+        fs::create_dir_all(separate.join("libs")).unwrap();
+        for name in ["libfoo.so", "libfoo.so.1"] {
+            fs::copy(
+                src.join("libs/libfoo.so.1"),
+                separate.join("libs").join(name),
+            )
+            .unwrap();
+        }
+
+        let bytes = archive(&src, TarFormat::Pax, HeaderOverrides::default()).unwrap();
+        let copies = archive(&separate, TarFormat::Pax, HeaderOverrides::default()).unwrap();
+        assert!(bytes.len() + 64 * 1024 <= copies.len());
+        assert!(entries(&bytes).contains(&(
+            "bundle/libs/libfoo.so.1".into(),
+            EntryKind::Hardlink,
+            "bundle/libs/libfoo.so".into()
+        )));
+
+        let dest = tmp.0.join("dest");
+        unpack_all(&bytes[..], &dest).unwrap();
+        let ino = |name: &str| {
+            fs::metadata(dest.join("bundle/libs").join(name))
+                .unwrap()
+                .ino()
+        };
+        assert_eq!(ino("libfoo.so"), ino("libfoo.so.1"));
+        assert_eq!(
+            fs::read(dest.join("bundle/libs/libfoo.so.1"))
+                .unwrap()
+                .len(),
+            64 * 1024
+        );
+    }
+}
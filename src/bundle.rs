@@ -0,0 +1,525 @@
+use crate::checksum::{Digest, Integrity};
+use crate::error::{Result, RexError};
+use crate::log::warning;
+use std::io::{self, Read};
+use std::mem::size_of;
+
+pub const MAGIC_MARKER: [u8; 10] = *b"REX_BUNDLE";
+pub const STDIN_FILE_NAME: &str = ".rex_stdin";
+pub const ENTRYPOINT_SCRIPT_NAME: &str = ".rex_entrypoint";
+pub const CAPABILITY_XATTR: &str = "security.capability";
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const PACK_THRESHOLD: usize = 256;
+
+/// Stamp in the launcher's read-only data. The generator sets its last byte
+/// in every launcher copy it turns into a bundle, so a bundle that lost its
+/// appended payload (e.g. to `strip`) can tell it is not the plain CLI.
+#[used]
+pub static LAUNCHER_STAMP: [u8; 16] = *b"REX-LAUNCHER-v1\0";
+
+pub fn launcher_is_stamped() -> bool {
+    unsafe { std::ptr::read_volatile(&LAUNCHER_STAMP[15]) != 0 }
+}
+
+/// Trailer layout version, written just before `MAGIC_MARKER`. Bundles from
+/// before it end in the `LEGACY_METADATA_SIZE` metadata instead (no options),
+/// whose last byte, the top byte of the name length, is always 0.
+pub const FORMAT_VERSION: u8 = 2;
+pub const LEGACY_METADATA_SIZE: usize = 12;
+
+#[repr(C, packed)]
+pub struct BundleMetadata {
+    pub payload_size: u64,
+    pub target_bin_name_len: u32,
+    pub options_len: u32,
+}
+
+const _: () = assert!(size_of::<BundleMetadata>() == 16);
+
+impl BundleMetadata {
+    pub fn to_bytes(&self) -> [u8; size_of::<BundleMetadata>()] {
+        let mut bytes = [0u8; size_of::<BundleMetadata>()];
+        bytes[0..8].copy_from_slice(&{ self.payload_size }.to_le_bytes());
+        bytes[8..12].copy_from_slice(&{ self.target_bin_name_len }.to_le_bytes());
+        bytes[12..16].copy_from_slice(&{ self.options_len }.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; size_of::<BundleMetadata>()]) -> Self {
+        Self {
+            payload_size: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            target_bin_name_len: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            options_len: u32::from_le_bytes(bytes[12..16].try_into().unwrap()),
+        }
+    }
+
+    pub fn from_legacy_bytes(bytes: &[u8; LEGACY_METADATA_SIZE]) -> Self {
+        Self {
+            payload_size: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            target_bin_name_len: u32::from_le_bytes(bytes[8..12].try_into().unwrap()),
+            options_len: 0,
+        }
+    }
+}
+
+/// Where the payload goes in the launcher copy (`--embed-mode`).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum EmbedMode {
+    #[default]
+    Append,
+    Section,
+}
+
+impl EmbedMode {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "append" => Ok(Self::Append),
+            "section" => Ok(Self::Section),
+            _ => Err(RexError::Usage(format!(
+                "Unknown embed mode '{value}' (expected append or section)"
+            ))),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Append => "append",
+            Self::Section => "section",
+        }
+    }
+}
+
+/// Payload compressor. Only zstd exists today; level validation and presets
+/// are keyed on it so another codec only has to add its own range here.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    Zstd,
+}
+
+impl Codec {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Zstd => "zstd",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "zstd" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn level_range(self) -> (i32, i32) {
+        match self {
+            Self::Zstd => (1, 22),
+        }
+    }
+
+    /// Brings `level` into this codec's range, warning when it had to, for
+    /// levels that did not come through `level` (library callers, stored
+    /// options).
+    pub fn clamp_level(self, level: i32) -> i32 {
+        let (min, max) = self.level_range();
+        let clamped = level.clamp(min, max);
+        if clamped != level {
+            warning!("[Warning] Clamping compression level {level} to {clamped}");
+        }
+        clamped
+    }
+
+    /// Resolves a numeric level or one of the `fast`/`default`/`best` presets
+    /// to this codec's native level, rejecting values outside its range.
+    pub fn level(self, value: &str) -> Result<i32> {
+        let (min, max) = self.level_range();
+        let level = match value {
+            "fast" => min,
+            "default" => 5,
+            "best" => max,
+            _ => value.parse().map_err(|_| {
+                RexError::Usage(format!(
+                    "Invalid compression level '{value}' (expected {min}–{max}, fast, default or best)"
+                ))
+            })?,
+        };
+        if !(min..=max).contains(&level) {
+            return Err(RexError::Usage(format!(
+                "level {level} invalid for {} ({min}–{max})",
+                self.name()
+            )));
+        }
+        Ok(level)
+    }
+}
+
+/// Settings baked in at generation time and read back by the runtime.
+/// Stored between the target name and the fixed metadata as `key=value`
+/// records separated by NUL bytes, compressed when large (see `pack`);
+/// unknown keys are ignored.
+#[derive(Debug, Default, Clone)]
+pub struct BundleOptions {
+    pub stdin_file: Option<String>,
+    pub timeout: Option<u64>,
+    pub store_size: Option<u64>,
+    /// Size of the payload TAR before compression, for extraction progress.
+    pub unpacked_size: Option<u64>,
+    pub payload_hash: Option<u64>,
+    /// How `digest` was computed; bundles without it are checked against
+    /// `payload_hash`.
+    pub integrity: Option<Integrity>,
+    pub digest: Option<String>,
+    pub codec: Option<Codec>,
+    pub level: Option<i32>,
+    pub embed_mode: Option<EmbedMode>,
+    pub cache: bool,
+    pub path_append: bool,
+    pub secure_cleanup: bool,
+    pub clean_env: bool,
+    pub clean_env_prefixes: Vec<String>,
+    pub keep_env: Vec<String>,
+    pub relinked: bool,
+    /// `PT_INTERP` that `--relink-rpath` gave the staged executables.
+    pub interpreter: Option<String>,
+    pub no_loader_wrapper: bool,
+    pub debug_info: bool,
+    pub arch: Option<String>,
+    /// Oldest host glibc the bundle runs on (`--target-glibc`).
+    pub min_glibc: Option<String>,
+    pub post_extract: Option<String>,
+    /// `--installer-mode` destination, used when the bundle is invoked with
+    /// `install_command` (default `install`) as its first argument.
+    pub install_dir: Option<String>,
+    pub install_command: Option<String>,
+    /// File the child PID is written to while it runs (`--pidfile`).
+    pub pidfile: Option<String>,
+    /// Script run with `sh` in place of the target (`--entrypoint-script`).
+    pub entrypoint_script: Option<String>,
+    /// Arguments passed to the target ahead of the ones given at launch.
+    pub prepend_args: Vec<String>,
+    /// Extra loader arguments (`--loader-option`), already split.
+    pub loader_args: Vec<String>,
+    /// `--toolbox` bundles: the selectable programs, the target first and
+    /// the others in `bins/`.
+    pub tools: Vec<String>,
+    /// `--dedup-across-bundles`: the store holding the libraries left out of
+    /// the payload, and each one's path in the tree with its SHA-256.
+    pub dedup_store: Option<String>,
+    pub blobs: Vec<(String, String)>,
+    /// `security.capability` values to restore, keyed by path relative to
+    /// the extraction directory.
+    pub capabilities: Vec<(String, Vec<u8>)>,
+}
+
+impl BundleOptions {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut push = |key: &str, value: &str| {
+            out.extend_from_slice(key.as_bytes());
+            out.push(b'=');
+            out.extend_from_slice(value.as_bytes());
+            out.push(0);
+        };
+
+        if let Some(file) = &self.stdin_file {
+            push("stdin_file", file);
+        }
+        if let Some(secs) = self.timeout {
+            push("timeout", &secs.to_string());
+        }
+        if let Some(size) = self.store_size {
+            push("store_size", &size.to_string());
+        }
+        if let Some(size) = self.unpacked_size {
+            push("unpacked_size", &size.to_string());
+        }
+        if let Some(hash) = self.payload_hash {
+            push("payload_hash", &format!("{hash:016x}"));
+        }
+        if let Some(mode) = self.integrity {
+            push("integrity", mode.name());
+        }
+        if let Some(digest) = &self.digest {
+            push("digest", digest);
+        }
+        if let Some(codec) = self.codec {
+            push("codec", codec.name());
+        }
+        if let Some(level) = self.level {
+            push("level", &level.to_string());
+        }
+        if let Some(mode) = self.embed_mode {
+            push("embed_mode", mode.name());
+        }
+        if self.cache {
+            push("cache", "1");
+        }
+        if self.path_append {
+            push("path_append", "1");
+        }
+        if self.secure_cleanup {
+            push("secure_cleanup", "1");
+        }
+        if self.clean_env {
+            push("clean_env", "1");
+        }
+        for prefix in &self.clean_env_prefixes {
+            push("clean_env_prefix", prefix);
+        }
+        for name in &self.keep_env {
+            push("keep_env", name);
+        }
+        if self.relinked {
+            push("relinked", "1");
+        }
+        if let Some(interp) = &self.interpreter {
+            push("interpreter", interp);
+        }
+        if self.no_loader_wrapper {
+            push("no_loader_wrapper", "1");
+        }
+        if self.debug_info {
+            push("debug_info", "1");
+        }
+        if let Some(arch) = &self.arch {
+            push("arch", arch);
+        }
+        if let Some(version) = &self.min_glibc {
+            push("min_glibc", version);
+        }
+        if let Some(hook) = &self.post_extract {
+            push("post_extract", hook);
+        }
+        if let Some(dir) = &self.install_dir {
+            push("install_dir", dir);
+        }
+        if let Some(command) = &self.install_command {
+            push("install_command", command);
+        }
+        if let Some(path) = &self.pidfile {
+            push("pidfile", path);
+        }
+        if let Some(script) = &self.entrypoint_script {
+            push("entrypoint_script", script);
+        }
+        // One record per argument, so arguments may contain commas.
+        for arg in &self.prepend_args {
+            push("prepend_arg", arg);
+        }
+        for tool in &self.tools {
+            push("tool", tool);
+        }
+        for arg in &self.loader_args {
+            push("loader_arg", arg);
+        }
+        if let Some(store) = &self.dedup_store {
+            push("dedup_store", store);
+        }
+        for (path, hash) in &self.blobs {
+            push("blob", &format!("{hash}:{path}"));
+        }
+        for (path, value) in &self.capabilities {
+            let hex: String = value.iter().map(|b| format!("{b:02x}")).collect();
+            push("capability", &format!("{hex}:{path}"));
+        }
+        out
+    }
+
+    /// Options as stored in the trailer: the encoded records, or a zstd frame
+    /// of them once they outgrow a few hundred bytes (long baked argument
+    /// lists, capabilities, ...). Records start with a key, never with the
+    /// frame magic, so `unpack` reads bundles from before this as well.
+    pub fn pack(&self) -> Result<Vec<u8>> {
+        let records = self.encode();
+        if records.len() < PACK_THRESHOLD {
+            return Ok(records);
+        }
+        let packed = zstd::stream::encode_all(&records[..], 19)?;
+        Ok(if packed.len() < records.len() {
+            packed
+        } else {
+            records
+        })
+    }
+
+    pub fn unpack(bytes: &[u8]) -> Result<Self> {
+        if bytes.starts_with(&ZSTD_MAGIC) {
+            let records = zstd::stream::decode_all(bytes)
+                .map_err(|e| RexError::BundleCorrupt(format!("invalid packed options: {e}")))?;
+            return Self::decode(&records);
+        }
+        Self::decode(bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let corrupt = |what: &str| RexError::BundleCorrupt(format!("invalid option {what}"));
+        let mut opts = Self::default();
+        for record in bytes.split(|b| *b == 0).filter(|r| !r.is_empty()) {
+            let record = std::str::from_utf8(record).map_err(|_| corrupt("encoding"))?;
+            let (key, value) = record.split_once('=').ok_or_else(|| corrupt(record))?;
+            match key {
+                "stdin_file" => opts.stdin_file = Some(value.to_string()),
+                "timeout" => opts.timeout = Some(value.parse().map_err(|_| corrupt(key))?),
+                "store_size" => opts.store_size = Some(value.parse().map_err(|_| corrupt(key))?),
+                "unpacked_size" => {
+                    opts.unpacked_size = Some(value.parse().map_err(|_| corrupt(key))?)
+                }
+                "payload_hash" => {
+                    opts.payload_hash =
+                        Some(u64::from_str_radix(value, 16).map_err(|_| corrupt(key))?)
+                }
+                "integrity" => {
+                    opts.integrity = Some(Integrity::parse(value).map_err(|_| corrupt(key))?)
+                }
+                "digest" => opts.digest = Some(value.to_string()),
+                "codec" => {
+                    opts.codec = Some(Codec::parse(value).ok_or_else(|| {
+                        RexError::BundleCorrupt(format!("unsupported codec '{value}'"))
+                    })?)
+                }
+                "level" => opts.level = Some(value.parse().map_err(|_| corrupt(key))?),
+                "embed_mode" => {
+                    opts.embed_mode = Some(EmbedMode::parse(value).map_err(|_| corrupt(key))?)
+                }
+                "cache" => opts.cache = value == "1",
+                "path_append" => opts.path_append = value == "1",
+                "secure_cleanup" => opts.secure_cleanup = value == "1",
+                "clean_env" => opts.clean_env = value == "1",
+                "clean_env_prefix" => opts.clean_env_prefixes.push(value.to_string()),
+                "keep_env" => opts.keep_env.push(value.to_string()),
+                "relinked" => opts.relinked = value == "1",
+                "interpreter" => opts.interpreter = Some(value.to_string()),
+                "no_loader_wrapper" => opts.no_loader_wrapper = value == "1",
+                "debug_info" => opts.debug_info = value == "1",
+                "arch" => opts.arch = Some(value.to_string()),
+                "min_glibc" => opts.min_glibc = Some(value.to_string()),
+                "post_extract" => opts.post_extract = Some(value.to_string()),
+                "install_dir" => opts.install_dir = Some(value.to_string()),
+                "install_command" => opts.install_command = Some(value.to_string()),
+                "pidfile" => opts.pidfile = Some(value.to_string()),
+                "entrypoint_script" => opts.entrypoint_script = Some(value.to_string()),
+                "prepend_arg" => opts.prepend_args.push(value.to_string()),
+                "tool" => opts.tools.push(value.to_string()),
+                "loader_arg" => opts.loader_args.push(value.to_string()),
+                "dedup_store" => opts.dedup_store = Some(value.to_string()),
+                "blob" => {
+                    let (hash, path) = value.split_once(':').ok_or_else(|| corrupt(key))?;
+                    opts.blobs.push((path.to_string(), hash.to_string()));
+                }
+                "capability" => {
+                    let (hex, path) = value.split_once(':').ok_or_else(|| corrupt(key))?;
+                    let bytes = (0..hex.len())
+                        .step_by(2)
+                        .map(|i| {
+                            hex.get(i..i + 2)
+                                .and_then(|b| u8::from_str_radix(b, 16).ok())
+                        })
+                        .collect::<Option<Vec<u8>>>()
+                        .ok_or_else(|| corrupt(key))?;
+                    opts.capabilities.push((path.to_string(), bytes));
+                }
+                _ => {}
+            }
+        }
+        Ok(opts)
+    }
+}
+
+/// 64-bit FNV-1a, used to identify a payload (e.g. for cache directories).
+pub struct Fnv64(u64);
+
+impl Fnv64 {
+    pub fn new() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+
+    pub fn update_reader(&mut self, mut reader: impl Read) -> io::Result<()> {
+        let mut buf = [0u8; 64 * 1024];
+        loop {
+            match reader.read(&mut buf)? {
+                0 => return Ok(()),
+                n => self.update(&buf[..n]),
+            }
+        }
+    }
+}
+
+/// Feeds every byte read through it into a `Digest`, so a payload can be
+/// checked while it is being extracted.
+pub struct HashingReader<'a, R: Read> {
+    inner: R,
+    hasher: &'a mut Digest,
+}
+
+impl<'a, R: Read> HashingReader<'a, R> {
+    pub fn new(inner: R, hasher: &'a mut Digest) -> Self {
+        Self { inner, hasher }
+    }
+}
+
+impl<R: Read> Read for HashingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_options_round_trip_one_record_per_value() {
+        let options = BundleOptions {
+            clean_env_prefixes: vec!["LC_".into(), "A,B".into()],
+            keep_env: vec!["HOME".into(), "TERM".into()],
+            prepend_args: vec!["--opt=a,b".into(), String::new()],
+            capabilities: vec![
+                ("bin/ping".into(), vec![0x01, 0x00, 0x00, 0x02]),
+                ("odd:name,x".into(), vec![0xff]),
+            ],
+            ..Default::default()
+        };
+        let records = options.encode();
+        let keys: Vec<&[u8]> = records
+            .split(|b| *b == 0)
+            .filter(|r| !r.is_empty())
+            .map(|r| r.split(|b| *b == b'=').next().unwrap())
+            .collect();
+        assert_eq!(keys.iter().filter(|k| **k == b"capability").count(), 2);
+        assert_eq!(keys.iter().filter(|k| **k == b"keep_env").count(), 2);
+        assert_eq!(
+            keys.iter().filter(|k| **k == b"clean_env_prefix").count(),
+            2
+        );
+
+        let decoded = BundleOptions::unpack(&options.pack().unwrap()).unwrap();
+        assert_eq!(decoded.clean_env_prefixes, options.clean_env_prefixes);
+        assert_eq!(decoded.keep_env, options.keep_env);
+        assert_eq!(decoded.prepend_args, options.prepend_args);
+        assert_eq!(decoded.capabilities, options.capabilities);
+    }
+
+    #[test]
+    fn malformed_capability_records_are_corrupt() {
+        for record in [
+            "capability=0100",
+            "capability=0g:bin/x",
+            "capability=010:bin/x",
+        ] {
+            let err = BundleOptions::decode(format!("{record}\0").as_bytes()).unwrap_err();
+            assert!(matches!(err, RexError::BundleCorrupt(_)), "{record}: {err}");
+        }
+    }
+}
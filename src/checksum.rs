@@ -0,0 +1,309 @@
+use crate::bundle::Fnv64;
+use crate::error::{Result, RexError};
+use std::io::{self, Read};
+
+/// How the runtime checks the payload before running it (`--integrity`).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Integrity {
+    None,
+    #[default]
+    Crc32,
+    Sha256,
+}
+
+impl Integrity {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "none" => Ok(Self::None),
+            "crc32" => Ok(Self::Crc32),
+            "sha256" => Ok(Self::Sha256),
+            _ => Err(RexError::Usage(format!(
+                "Unknown integrity mode '{value}' (expected none, crc32 or sha256)"
+            ))),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Crc32 => "crc32",
+            Self::Sha256 => "sha256",
+        }
+    }
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-32 (IEEE), as used by gzip and zip.
+pub struct Crc32(u32);
+
+impl Crc32 {
+    fn update(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 = CRC32_TABLE[((self.0 ^ *b as u32) & 0xff) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// SHA-256 (FIPS 180-4).
+pub struct Sha256 {
+    state: [u32; 8],
+    block: [u8; 64],
+    filled: usize,
+    len: u64,
+}
+
+impl Sha256 {
+    fn new() -> Self {
+        Self {
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            block: [0; 64],
+            filled: 0,
+            len: 0,
+        }
+    }
+
+    fn compress(&mut self) {
+        let mut w = [0u32; 64];
+        for (i, word) in self.block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(SHA256_K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (s, v) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+
+    fn update(&mut self, mut bytes: &[u8]) {
+        self.len += bytes.len() as u64;
+        while !bytes.is_empty() {
+            let n = (64 - self.filled).min(bytes.len());
+            self.block[self.filled..self.filled + n].copy_from_slice(&bytes[..n]);
+            self.filled += n;
+            bytes = &bytes[n..];
+            if self.filled == 64 {
+                self.compress();
+                self.filled = 0;
+            }
+        }
+    }
+
+    fn finish(mut self) -> [u8; 32] {
+        let bits = self.len.wrapping_mul(8);
+        self.update(&[0x80]);
+        while self.filled != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bits.to_be_bytes());
+        let mut out = [0u8; 32];
+        for (chunk, s) in out.chunks_exact_mut(4).zip(self.state) {
+            chunk.copy_from_slice(&s.to_be_bytes());
+        }
+        out
+    }
+}
+
+/// Running payload checksum for the selected `Integrity` mode. `Fnv` is the
+/// FNV-1a hash that bundles built before `--integrity` were verified with.
+pub enum Digest {
+    None,
+    Fnv(Fnv64),
+    Crc32(Crc32),
+    Sha256(Box<Sha256>),
+}
+
+impl Digest {
+    pub fn new(mode: Integrity) -> Self {
+        match mode {
+            Integrity::None => Self::None,
+            Integrity::Crc32 => Self::Crc32(Crc32(!0)),
+            Integrity::Sha256 => Self::Sha256(Box::new(Sha256::new())),
+        }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Self::None => {}
+            Self::Fnv(h) => h.update(bytes),
+            Self::Crc32(h) => h.update(bytes),
+            Self::Sha256(h) => h.update(bytes),
+        }
+    }
+
+    pub fn update_reader(&mut self, mut reader: impl Read) -> io::Result<()> {
+        let mut buf = [0u8; 64 * 1024];
+        loop {
+            match reader.read(&mut buf)? {
+                0 => return Ok(()),
+                n => self.update(&buf[..n]),
+            }
+        }
+    }
+
+    /// Lowercase hex digest; empty for `Integrity::None`.
+    pub fn finish_hex(self) -> String {
+        match self {
+            Self::None => String::new(),
+            Self::Fnv(h) => format!("{:016x}", h.finish()),
+            Self::Crc32(h) => format!("{:08x}", h.finish()),
+            Self::Sha256(h) => h.finish().iter().map(|b| format!("{b:02x}")).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(mode: Integrity, bytes: &[u8]) -> String {
+        let mut digest = Digest::new(mode);
+        digest.update(bytes);
+        digest.finish_hex()
+    }
+
+    #[test]
+    fn sha256_known_answers() {
+        let cases: [(&[u8], &str); 3] = [
+            (
+                b"",
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            ),
+            (
+                b"abc",
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            ),
+            (
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(hex(Integrity::Sha256, input), expected);
+        }
+        assert_eq!(
+            hex(Integrity::Sha256, &vec![b'a'; 1_000_000]),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+
+    // Lengths around the point where the length field no longer fits in the
+    // last block (55/56) and around a full block (63/64/65).
+    #[test]
+    fn sha256_padding_boundaries() {
+        let cases = [
+            (
+                55,
+                "9f4390f8d30c2dd92ec9f095b65e2b9ae9b0a925a5258e241c9f1e910f734318",
+            ),
+            (
+                56,
+                "b35439a4ac6f0948b6d6f9e3c6af0f5f590ce20f1bde7090ef7970686ec6738a",
+            ),
+            (
+                63,
+                "7d3e74a05d7db15bce4ad9ec0658ea98e3f06eeecf16b4c6fff2da457ddc2f34",
+            ),
+            (
+                64,
+                "ffe054fe7ae0cb6dc65c3af9b61d5209f439851db43d0ba5997337df154668eb",
+            ),
+            (
+                65,
+                "635361c48bb9eab14198e76ea8ab7f1a41685d6ad62aa9146d301d4f17eb0ae0",
+            ),
+        ];
+        for (len, expected) in cases {
+            assert_eq!(hex(Integrity::Sha256, &vec![b'a'; len]), expected, "{len}");
+        }
+    }
+
+    #[test]
+    fn split_updates_match_one_shot() {
+        let input: Vec<u8> = (0..200u8).collect();
+        for mode in [Integrity::Crc32, Integrity::Sha256] {
+            let expected = hex(mode, &input);
+            for split in 0..input.len() {
+                let mut digest = Digest::new(mode);
+                digest.update(&input[..split]);
+                digest.update(&input[split..]);
+                assert_eq!(digest.finish_hex(), expected, "{} at {split}", mode.name());
+            }
+        }
+    }
+
+    #[test]
+    fn crc32_known_answer() {
+        assert_eq!(hex(Integrity::Crc32, b"123456789"), "cbf43926");
+        assert_eq!(hex(Integrity::Crc32, b""), "00000000");
+    }
+
+    #[test]
+    fn none_has_no_digest() {
+        assert_eq!(hex(Integrity::None, b"abc"), "");
+    }
+}
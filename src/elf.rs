@@ -0,0 +1,710 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_STRTAB: u64 = 5;
+const DT_SONAME: u64 = 14;
+const DT_RPATH: u64 = 15;
+const DT_RUNPATH: u64 = 29;
+const LOADER_PREFIXES: &[&str] = &["ld-linux", "ld-musl"];
+const GLIBC_RELEASE: &[u8] = b"release version ";
+const SHT_PROGBITS: u64 = 1;
+const SHN_LORESERVE: usize = 0xff00;
+const MAX_SECTION_NAMES: u64 = 1 << 20;
+
+/// Section holding the payload and trailer of `--embed-mode section` bundles.
+pub const PAYLOAD_SECTION: &str = ".rex_payload";
+
+/// Whether `path` names a dynamic loader (`ld-linux*`, `ld-musl*`). Staging
+/// transforms (RPATH patching, mode normalization, ...) must leave these
+/// untouched, since a modified loader often no longer starts.
+pub fn is_loader(path: &Path) -> bool {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    LOADER_PREFIXES.iter().any(|p| name.starts_with(p))
+}
+
+/// glibc release of a loader or `libc.so.6`, read from the banner both embed
+/// (`... stable release version 2.36.`). `None` for musl and other libcs.
+pub fn glibc_version(path: &Path) -> io::Result<Option<String>> {
+    let data = fs::read(path)?;
+    let Some(start) = data
+        .windows(GLIBC_RELEASE.len())
+        .position(|w| w == GLIBC_RELEASE)
+        .map(|i| i + GLIBC_RELEASE.len())
+    else {
+        return Ok(None);
+    };
+    let len = data[start..]
+        .iter()
+        .take_while(|b| b.is_ascii_digit() || **b == b'.')
+        .count();
+    let version = String::from_utf8_lossy(&data[start..start + len]);
+    let version = version.trim_end_matches('.');
+    Ok((!version.is_empty()).then(|| version.to_string()))
+}
+
+/// `major.minor` of a glibc version such as `2.36` or `2.17.1`.
+pub fn parse_glibc_version(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next().unwrap_or("0").parse().ok()?;
+    Some((major, minor))
+}
+
+/// Cheap sanity check of an ELF header, so empty, truncated and non-ELF files
+/// can be reported as such before handing them to the dependency resolver.
+pub fn check_header(path: &Path) -> io::Result<Option<&'static str>> {
+    let mut header = Vec::with_capacity(0x40);
+    fs::File::open(path)?.take(0x40).read_to_end(&mut header)?;
+    if header.is_empty() {
+        return Ok(Some("empty file"));
+    }
+    if !header.starts_with(b"\x7fELF") {
+        return Ok(Some("no ELF magic"));
+    }
+    let min_len = if header.get(4) == Some(&2) {
+        0x40
+    } else {
+        0x34
+    };
+    if header.len() < min_len {
+        return Ok(Some("truncated header"));
+    }
+    Ok(None)
+}
+
+fn uint(bytes: &[u8], is_le: bool) -> u64 {
+    let mut n = 0u64;
+    for i in 0..bytes.len() {
+        let b = if is_le {
+            bytes[bytes.len() - 1 - i]
+        } else {
+            bytes[i]
+        };
+        n = (n << 8) | b as u64;
+    }
+    n
+}
+
+fn put_uint(bytes: &mut [u8], value: u64, is_le: bool) {
+    let len = bytes.len();
+    for i in 0..len {
+        let b = (value >> (8 * i)) as u8;
+        if is_le {
+            bytes[i] = b;
+        } else {
+            bytes[len - 1 - i] = b;
+        }
+    }
+}
+
+// Section header table of a file, read with a few seeks instead of loading
+// the whole (possibly bundled, hence large) file like `Elf::open` does.
+struct SectionTable {
+    header: Vec<u8>,
+    is_64: bool,
+    is_le: bool,
+    entries: Vec<u8>,
+    entry_size: usize,
+    names_index: usize,
+    names: Vec<u8>,
+}
+
+impl SectionTable {
+    fn read(file: &mut File) -> io::Result<Option<Self>> {
+        let mut header = Vec::with_capacity(0x40);
+        (&*file).take(0x40).read_to_end(&mut header)?;
+        if header.len() < 0x34 || !header.starts_with(b"\x7fELF") {
+            return Ok(None);
+        }
+        let is_64 = header[4] == 2;
+        let is_le = header[5] == 1;
+        if is_64 && header.len() < 0x40 {
+            return Ok(None);
+        }
+        let (shoff, entry_size, count, names_index) = if is_64 {
+            (&header[0x28..0x30], 0x3a, 0x3c, 0x3e)
+        } else {
+            (&header[0x20..0x24], 0x2e, 0x30, 0x32)
+        };
+        let shoff = uint(shoff, is_le);
+        let field = |off: usize| uint(&header[off..off + 2], is_le) as usize;
+        let (entry_size, count, names_index) =
+            (field(entry_size), field(count), field(names_index));
+        let min_entry = if is_64 { 0x40 } else { 0x28 };
+        if shoff == 0 || count == 0 || entry_size < min_entry || names_index >= count {
+            return Ok(None);
+        }
+        // Both sizes come from the header, so check them against the file
+        // before allocating up to 4 GiB for a crafted one.
+        let table_size = (entry_size * count) as u64;
+        if shoff.saturating_add(table_size) > file.metadata()?.len() {
+            return Ok(None);
+        }
+
+        let mut entries = vec![0u8; table_size as usize];
+        file.seek(SeekFrom::Start(shoff))?;
+        file.read_exact(&mut entries)?;
+        let mut table = Self {
+            header,
+            is_64,
+            is_le,
+            entries,
+            entry_size,
+            names_index,
+            names: vec![],
+        };
+
+        let (names_offset, names_size) = table.location(names_index);
+        if names_size > MAX_SECTION_NAMES {
+            return Ok(None);
+        }
+        let mut names = vec![0u8; names_size as usize];
+        file.seek(SeekFrom::Start(names_offset))?;
+        file.read_exact(&mut names)?;
+        table.names = names;
+        Ok(Some(table))
+    }
+
+    // (offset, width) of sh_name, sh_type, sh_offset and sh_size in an entry.
+    fn fields(&self) -> [(usize, usize); 4] {
+        if self.is_64 {
+            [(0, 4), (4, 4), (0x18, 8), (0x20, 8)]
+        } else {
+            [(0, 4), (4, 4), (0x10, 4), (0x14, 4)]
+        }
+    }
+
+    fn entry(&self, index: usize) -> &[u8] {
+        &self.entries[index * self.entry_size..(index + 1) * self.entry_size]
+    }
+
+    fn location(&self, index: usize) -> (u64, u64) {
+        let [_, _, (offset, offset_len), (size, size_len)] = self.fields();
+        let entry = self.entry(index);
+        (
+            uint(&entry[offset..offset + offset_len], self.is_le),
+            uint(&entry[size..size + size_len], self.is_le),
+        )
+    }
+
+    fn name(&self, index: usize) -> Option<&[u8]> {
+        let [(name, name_len), ..] = self.fields();
+        let start = uint(&self.entry(index)[name..name + name_len], self.is_le) as usize;
+        let bytes = self.names.get(start..)?;
+        Some(&bytes[..bytes.iter().position(|b| *b == 0)?])
+    }
+
+    fn count(&self) -> usize {
+        self.entries.len() / self.entry_size
+    }
+}
+
+/// File offset and size of the section called `name`, if `path` is an ELF
+/// file with a section header table that has one.
+pub fn find_section(path: &Path, name: &str) -> io::Result<Option<(u64, u64)>> {
+    let Some(table) = SectionTable::read(&mut File::open(path)?)? else {
+        return Ok(None);
+    };
+    Ok((0..table.count())
+        .find(|i| table.name(*i) == Some(name.as_bytes()))
+        .map(|i| table.location(i)))
+}
+
+/// Declares the `size` bytes at `offset` (already in the file, past the end
+/// of the original image) as a new non-loaded section `name`. The
+/// section-name table and the section header table are rewritten at the end
+/// of the file; the old copies stay behind unused, as with `objcopy`.
+pub fn add_section(path: &Path, name: &str, offset: u64, size: u64) -> io::Result<()> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let Some(mut table) = SectionTable::read(&mut file)? else {
+        return Err(io::Error::other(format!(
+            "{} has no section header table",
+            path.display()
+        )));
+    };
+    let count = table.count();
+    if count + 1 >= SHN_LORESERVE {
+        return Err(io::Error::other(format!(
+            "{} has too many sections",
+            path.display()
+        )));
+    }
+
+    let name_index = table.names.len() as u64;
+    table.names.extend_from_slice(name.as_bytes());
+    table.names.push(0);
+    let names_offset = file.seek(SeekFrom::End(0))?;
+    file.write_all(&table.names)?;
+    let padding = (8 - (names_offset + table.names.len() as u64) % 8) % 8;
+    file.write_all(&vec![0u8; padding as usize])?;
+    let table_offset = names_offset + table.names.len() as u64 + padding;
+
+    let fields = table.fields();
+    let [
+        (name_off, name_len),
+        (type_off, type_len),
+        (off, off_len),
+        (len, len_len),
+    ] = fields;
+    let is_le = table.is_le;
+    let names_size = table.names.len() as u64;
+    let entry_size = table.entry_size;
+    let names_entry =
+        &mut table.entries[table.names_index * entry_size..(table.names_index + 1) * entry_size];
+    put_uint(&mut names_entry[off..off + off_len], names_offset, is_le);
+    put_uint(&mut names_entry[len..len + len_len], names_size, is_le);
+
+    let mut entry = vec![0u8; entry_size];
+    put_uint(&mut entry[name_off..name_off + name_len], name_index, is_le);
+    put_uint(
+        &mut entry[type_off..type_off + type_len],
+        SHT_PROGBITS,
+        is_le,
+    );
+    put_uint(&mut entry[off..off + off_len], offset, is_le);
+    put_uint(&mut entry[len..len + len_len], size, is_le);
+    file.write_all(&table.entries)?;
+    file.write_all(&entry)?;
+
+    let (shoff, shnum) = if table.is_64 {
+        (0x28..0x30, 0x3c..0x3e)
+    } else {
+        (0x20..0x24, 0x30..0x32)
+    };
+    put_uint(&mut table.header[shoff], table_offset, is_le);
+    put_uint(&mut table.header[shnum], count as u64 + 1, is_le);
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&table.header)
+}
+
+struct ProgramHeader {
+    kind: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+}
+
+pub struct Elf {
+    data: Vec<u8>,
+    is_64: bool,
+    is_le: bool,
+}
+
+impl Elf {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let data = fs::read(path)?;
+        if data.len() < 0x34 || !data.starts_with(b"\x7fELF") {
+            return Err(io::Error::other(format!(
+                "not an ELF binary: {}",
+                path.display()
+            )));
+        }
+        let is_64 = data[4] == 2;
+        let is_le = data[5] == 1;
+        Ok(Self { data, is_64, is_le })
+    }
+
+    fn read(&self, off: usize, len: usize) -> Option<u64> {
+        Some(uint(self.data.get(off..off.checked_add(len)?)?, self.is_le))
+    }
+
+    fn word(&self, off: usize) -> Option<u64> {
+        self.read(off, if self.is_64 { 8 } else { 4 })
+    }
+
+    fn program_headers(&self) -> Vec<ProgramHeader> {
+        let (phoff, phentsize, phnum) = if self.is_64 {
+            (self.word(0x20), self.read(0x36, 2), self.read(0x38, 2))
+        } else {
+            (self.word(0x1c), self.read(0x2a, 2), self.read(0x2c, 2))
+        };
+        let (Some(phoff), Some(size), Some(num)) = (phoff, phentsize, phnum) else {
+            return vec![];
+        };
+
+        (0..num)
+            .filter_map(|i| {
+                let base = usize::try_from(phoff.checked_add(i.checked_mul(size)?)?).ok()?;
+                // Inside the file, so the field offsets below cannot overflow.
+                if base >= self.data.len() {
+                    return None;
+                }
+                let kind = self.read(base, 4)? as u32;
+                let (offset, vaddr, filesz) = if self.is_64 {
+                    (
+                        self.word(base + 0x08)?,
+                        self.word(base + 0x10)?,
+                        self.word(base + 0x20)?,
+                    )
+                } else {
+                    (
+                        self.word(base + 0x04)?,
+                        self.word(base + 0x08)?,
+                        self.word(base + 0x10)?,
+                    )
+                };
+                Some(ProgramHeader {
+                    kind,
+                    offset,
+                    vaddr,
+                    filesz,
+                })
+            })
+            .collect()
+    }
+
+    fn c_str(&self, off: usize) -> Option<String> {
+        let bytes = self.data.get(off..)?;
+        let end = bytes.iter().position(|b| *b == 0)?;
+        Some(String::from_utf8_lossy(&bytes[..end]).into_owned())
+    }
+
+    /// Architecture in `std::env::consts::ARCH` naming, from `e_machine`.
+    pub fn arch(&self) -> Option<&'static str> {
+        Some(match self.read(0x12, 2)? {
+            3 => "x86",
+            8 if self.is_64 => "mips64",
+            8 => "mips",
+            20 => "powerpc",
+            21 => "powerpc64",
+            22 => "s390x",
+            40 => "arm",
+            62 => "x86_64",
+            183 => "aarch64",
+            243 if self.is_64 => "riscv64",
+            243 => "riscv32",
+            258 => "loongarch64",
+            _ => return None,
+        })
+    }
+
+    /// Path of the program interpreter (`PT_INTERP`), if any.
+    pub fn interpreter(&self) -> Option<String> {
+        self.program_headers()
+            .into_iter()
+            .find(|ph| ph.kind == PT_INTERP)
+            .and_then(|ph| self.c_str(ph.offset as usize))
+    }
+
+    fn vaddr_to_offset(&self, vaddr: u64) -> Option<u64> {
+        self.program_headers()
+            .into_iter()
+            .find(|ph| {
+                ph.kind == PT_LOAD
+                    && ph
+                        .vaddr
+                        .checked_add(ph.filesz)
+                        .is_some_and(|end| (ph.vaddr..end).contains(&vaddr))
+            })
+            .and_then(|ph| (vaddr - ph.vaddr).checked_add(ph.offset))
+    }
+
+    /// Sonames listed as `DT_NEEDED` in the dynamic section.
+    pub fn needed(&self) -> Vec<String> {
+        self.dynamic_strings(DT_NEEDED)
+    }
+
+    /// The library's own `DT_SONAME`, if it declares one.
+    pub fn soname(&self) -> Option<String> {
+        self.dynamic_strings(DT_SONAME).into_iter().next()
+    }
+
+    /// Library search directories from `DT_RUNPATH` (or the legacy
+    /// `DT_RPATH` without one) with `$ORIGIN`, `$LIB` and `$PLATFORM`
+    /// expanded as the loader would for this object loaded from `path`.
+    /// `$LIB` becomes `lib64`/`lib`, as on distributions without multiarch.
+    pub fn search_path(&self, path: &Path) -> Vec<PathBuf> {
+        let mut entries = self.dynamic_strings(DT_RUNPATH);
+        if entries.is_empty() {
+            entries = self.dynamic_strings(DT_RPATH);
+        }
+        let origin = fs::canonicalize(path)
+            .ok()
+            .and_then(|p| Some(p.parent()?.to_path_buf()))
+            .unwrap_or_default();
+        let lib = if self.is_64 { "lib64" } else { "lib" };
+        let platform = match self.arch() {
+            Some("x86") => "i686",
+            Some(arch) => arch,
+            None => "",
+        };
+        entries
+            .iter()
+            .flat_map(|entry| entry.split(':'))
+            .filter(|dir| !dir.is_empty())
+            .map(|dir| {
+                let mut dir = dir.to_string();
+                for (token, value) in [
+                    ("ORIGIN", origin.to_string_lossy().as_ref()),
+                    ("LIB", lib),
+                    ("PLATFORM", platform),
+                ] {
+                    dir = dir
+                        .replace(&format!("${{{token}}}"), value)
+                        .replace(&format!("${token}"), value);
+                }
+                PathBuf::from(dir)
+            })
+            .collect()
+    }
+
+    // String values of every dynamic entry with `wanted` tag.
+    fn dynamic_strings(&self, wanted: u64) -> Vec<String> {
+        let Some(dynamic) = self
+            .program_headers()
+            .into_iter()
+            .find(|ph| ph.kind == PT_DYNAMIC)
+        else {
+            return vec![];
+        };
+        let word = if self.is_64 { 8 } else { 4 };
+
+        let mut offsets = vec![];
+        let mut strtab = None;
+        for i in 0..dynamic.filesz / (2 * word) {
+            let Some(off) = dynamic
+                .offset
+                .checked_add(i * 2 * word)
+                .and_then(|off| usize::try_from(off).ok())
+            else {
+                break;
+            };
+            let (Some(tag), Some(val)) =
+                (self.word(off), self.word(off.saturating_add(word as usize)))
+            else {
+                break;
+            };
+            match tag {
+                DT_NULL => break,
+                DT_STRTAB => strtab = Some(val),
+                tag if tag == wanted => offsets.push(val),
+                _ => {}
+            }
+        }
+
+        let Some(strtab) = strtab.and_then(|addr| self.vaddr_to_offset(addr)) else {
+            return vec![];
+        };
+        offsets
+            .into_iter()
+            .filter_map(|n| self.c_str(usize::try_from(strtab.checked_add(n)?).ok()?))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::env;
+
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, bytes: &[u8]) -> Self {
+            let path = env::temp_dir().join(format!("rex-elf-{name}-{}", std::process::id()));
+            fs::write(&path, bytes).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    // A little-endian ELF64 header followed by `phdrs` (type, offset,
+    // vaddr, filesz) and `tail`, which starts at `data_offset(phdrs.len())`.
+    fn elf64(phdrs: &[(u32, u64, u64, u64)], tail: &[u8]) -> Vec<u8> {
+        let mut out = vec![0u8; 0x40];
+        out[..7].copy_from_slice(b"\x7fELF\x02\x01\x01");
+        out[0x12] = 62;
+        out[0x20..0x28].copy_from_slice(&0x40u64.to_le_bytes());
+        out[0x36..0x38].copy_from_slice(&0x38u16.to_le_bytes());
+        out[0x38..0x3a].copy_from_slice(&(phdrs.len() as u16).to_le_bytes());
+        for &(kind, offset, vaddr, filesz) in phdrs {
+            let mut ph = vec![0u8; 0x38];
+            ph[..4].copy_from_slice(&kind.to_le_bytes());
+            ph[0x08..0x10].copy_from_slice(&offset.to_le_bytes());
+            ph[0x10..0x18].copy_from_slice(&vaddr.to_le_bytes());
+            ph[0x20..0x28].copy_from_slice(&filesz.to_le_bytes());
+            out.extend(ph);
+        }
+        out.extend_from_slice(tail);
+        out
+    }
+
+    fn data_offset(phnum: usize) -> u64 {
+        0x40 + 0x38 * phnum as u64
+    }
+
+    fn dynamic(entries: &[(u64, u64)]) -> Vec<u8> {
+        entries
+            .iter()
+            .flat_map(|(tag, val)| [tag.to_le_bytes(), val.to_le_bytes()])
+            .flatten()
+            .collect()
+    }
+
+    /// A minimal x86_64 shared object with the given `DT_NEEDED`,
+    /// `DT_RUNPATH` and `DT_RPATH` entries, loaded at address 0.
+    pub(crate) fn dynamic_elf(
+        needed: &[&str],
+        runpath: Option<&str>,
+        rpath: Option<&str>,
+    ) -> Vec<u8> {
+        let strtab = data_offset(2);
+        let mut strings = vec![0u8];
+        let mut entries = vec![];
+        let mut add = |tag, value: &str| {
+            entries.push((tag, strings.len() as u64));
+            strings.extend_from_slice(value.as_bytes());
+            strings.push(0);
+        };
+        for name in needed {
+            add(DT_NEEDED, name);
+        }
+        if let Some(runpath) = runpath {
+            add(DT_RUNPATH, runpath);
+        }
+        if let Some(rpath) = rpath {
+            add(DT_RPATH, rpath);
+        }
+        strings.resize(strings.len().next_multiple_of(8), 0);
+        entries.extend([(DT_STRTAB, strtab), (DT_NULL, 0)]);
+        let entries = dynamic(&entries);
+        let dynamic_offset = strtab + strings.len() as u64;
+        let size = dynamic_offset + entries.len() as u64;
+        let phdrs = [
+            (PT_LOAD, 0, 0, size),
+            (
+                PT_DYNAMIC,
+                dynamic_offset,
+                dynamic_offset,
+                entries.len() as u64,
+            ),
+        ];
+        elf64(&phdrs, &[strings, entries].concat())
+    }
+
+    #[test]
+    fn check_header_rejects_empty_truncated_and_foreign_files() {
+        let empty = TempFile::new("empty", b"");
+        assert_eq!(check_header(&empty.0).unwrap(), Some("empty file"));
+
+        let truncated = TempFile::new("truncated", b"\x7fELF\x02\x01\x01\0");
+        assert_eq!(
+            check_header(&truncated.0).unwrap(),
+            Some("truncated header")
+        );
+
+        let script = TempFile::new("script", b"#!/bin/sh\necho not an ELF file at all\n");
+        assert_eq!(check_header(&script.0).unwrap(), Some("no ELF magic"));
+
+        let header = TempFile::new("header", &elf64(&[], &[]));
+        assert_eq!(check_header(&header.0).unwrap(), None);
+    }
+
+    #[test]
+    fn check_header_reports_missing_files() {
+        let missing = env::temp_dir().join(format!("rex-elf-missing-{}", std::process::id()));
+        let err = check_header(&missing).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn crafted_program_headers_do_not_panic() {
+        let mut bytes = elf64(&[], &[]);
+        bytes[0x20..0x28].copy_from_slice(&(u64::MAX - 0x10).to_le_bytes());
+        bytes[0x36..0x38].copy_from_slice(&u16::MAX.to_le_bytes());
+        bytes[0x38..0x3a].copy_from_slice(&u16::MAX.to_le_bytes());
+        let file = TempFile::new("phoff", &bytes);
+        let elf = Elf::open(&file.0).unwrap();
+        assert_eq!(elf.interpreter(), None);
+        assert!(elf.needed().is_empty());
+    }
+
+    #[test]
+    fn crafted_dynamic_section_does_not_panic() {
+        // A PT_LOAD whose end wraps around, a DT_NEEDED offset that overflows
+        // the string table address, and a PT_DYNAMIC at the end of memory.
+        let entries = dynamic(&[(DT_NEEDED, u64::MAX), (DT_STRTAB, 0x10), (DT_NULL, 0)]);
+        let phdrs = [
+            (PT_LOAD, 0, u64::MAX - 1, 0x100),
+            (PT_LOAD, 0, 0, 0x1000),
+            (PT_DYNAMIC, data_offset(3), 0, entries.len() as u64),
+        ];
+        let file = TempFile::new("dynamic", &elf64(&phdrs, &entries));
+        assert!(Elf::open(&file.0).unwrap().needed().is_empty());
+
+        let phdrs = [(PT_DYNAMIC, u64::MAX - 8, 0, 0x100)];
+        let file = TempFile::new("dynamic-end", &elf64(&phdrs, &[]));
+        assert!(Elf::open(&file.0).unwrap().needed().is_empty());
+    }
+
+    #[test]
+    fn oversized_section_table_is_not_allocated() {
+        let mut bytes = elf64(&[], &[]);
+        bytes[0x28..0x30].copy_from_slice(&0x40u64.to_le_bytes());
+        bytes[0x3a..0x3c].copy_from_slice(&u16::MAX.to_le_bytes());
+        bytes[0x3c..0x3e].copy_from_slice(&u16::MAX.to_le_bytes());
+        let file = TempFile::new("shnum", &bytes);
+        assert_eq!(find_section(&file.0, PAYLOAD_SECTION).unwrap(), None);
+    }
+
+    #[test]
+    fn reads_needed_and_soname_free_objects() {
+        let file = TempFile::new(
+            "needed",
+            &dynamic_elf(&["libfoo.so.1", "libc.so.6"], None, None),
+        );
+        let elf = Elf::open(&file.0).unwrap();
+        assert_eq!(elf.needed(), ["libfoo.so.1", "libc.so.6"]);
+        assert_eq!(elf.soname(), None);
+        assert_eq!(elf.arch(), Some("x86_64"));
+    }
+
+    #[test]
+    fn search_path_expands_dynamic_string_tokens() {
+        let dir = env::temp_dir().join(format!("rex-elf-origin-{}", std::process::id()));
+        fs::create_dir_all(dir.join("bin")).unwrap();
+        let path = dir.join("bin/app");
+        let runpath = "$ORIGIN/lib:${ORIGIN}/../share::/opt/$LIB/$PLATFORM:/usr/${LIB}/${PLATFORM}";
+        fs::write(&path, dynamic_elf(&["libpriv.so"], Some(runpath), None)).unwrap();
+
+        let origin = fs::canonicalize(dir.join("bin")).unwrap();
+        let dirs = Elf::open(&path).unwrap().search_path(&path);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            dirs,
+            [
+                origin.join("lib"),
+                origin.join("../share"),
+                PathBuf::from("/opt/lib64/x86_64"),
+                PathBuf::from("/usr/lib64/x86_64"),
+            ]
+        );
+    }
+
+    #[test]
+    fn search_path_prefers_runpath_over_rpath() {
+        let both = TempFile::new("both", &dynamic_elf(&[], Some("/run"), Some("/rpath")));
+        assert_eq!(
+            Elf::open(&both.0).unwrap().search_path(&both.0),
+            [PathBuf::from("/run")]
+        );
+        let legacy = TempFile::new("rpath", &dynamic_elf(&[], None, Some("/a:/b")));
+        assert_eq!(
+            Elf::open(&legacy.0).unwrap().search_path(&legacy.0),
+            [PathBuf::from("/a"), PathBuf::from("/b")]
+        );
+        let none = TempFile::new("norunpath", &dynamic_elf(&["libc.so.6"], None, None));
+        assert!(Elf::open(&none.0).unwrap().search_path(&none.0).is_empty());
+    }
+}
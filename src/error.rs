@@ -0,0 +1,162 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub enum RexError {
+    Io(io::Error),
+    Usage(String),
+    NotElf {
+        path: PathBuf,
+        reason: &'static str,
+    },
+    NotSharedElf(PathBuf),
+    Resolve {
+        path: PathBuf,
+        cause: String,
+    },
+    /// A dependency no search path provides; fatal under `--strict-deps`.
+    MissingDependency {
+        lib: String,
+        needed_by: PathBuf,
+    },
+    TooManyDependencies {
+        max: usize,
+        /// Every resolved library, with the binary that needed it.
+        libs: Vec<String>,
+    },
+    LibraryConflict(Vec<String>),
+    LoaderAbiMismatch {
+        loader: String,
+        loader_version: String,
+        libc_version: String,
+    },
+    CopyFailed {
+        path: PathBuf,
+        cause: String,
+    },
+    OwnerChange {
+        path: PathBuf,
+        cause: io::Error,
+    },
+    /// An external tool (`patchelf`, `strip`, ...) could not be started.
+    ToolNotRun {
+        tool: String,
+        flag: String,
+        cause: io::Error,
+    },
+    ToolFailed {
+        tool: String,
+        path: PathBuf,
+    },
+    ChecksFailed(usize),
+    CachePublish(PathBuf),
+    Archive(String),
+    BundleCorrupt(String),
+    LoaderNotFound,
+    Launch(String),
+    ProgramFailed {
+        name: String,
+        code: i32,
+    },
+    HookFailed {
+        hook: String,
+        code: i32,
+    },
+    Timeout(u64),
+    SizeLimit {
+        what: &'static str,
+        size: u64,
+        limit: u64,
+    },
+    UnsupportedHost(&'static str),
+}
+
+pub type Result<T> = std::result::Result<T, RexError>;
+
+impl fmt::Display for RexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{e}"),
+            Self::Usage(msg) => write!(f, "{msg}"),
+            Self::NotElf { path, reason } => {
+                write!(f, "not an ELF binary: {} ({reason})", path.display())
+            }
+            Self::NotSharedElf(path) => write!(f, "Not Shared ELF binary: {}", path.display()),
+            Self::Resolve { path, cause } => write!(
+                f,
+                "Failed to analyze dependencies of {}: {cause}",
+                path.display()
+            ),
+            Self::MissingDependency { lib, needed_by } => write!(
+                f,
+                "unresolved dependency: {lib} (needed by {})",
+                needed_by.display()
+            ),
+            Self::TooManyDependencies { max, libs } => write!(
+                f,
+                "resolved {} unique libraries, more than --max-deps {max}:\n{}",
+                libs.len(),
+                libs.join("\n")
+            ),
+            Self::LibraryConflict(conflicts) => {
+                write!(f, "conflicting library versions: {}", conflicts.join("; "))
+            }
+            Self::LoaderAbiMismatch {
+                loader,
+                loader_version,
+                libc_version,
+            } => write!(
+                f,
+                "{loader} is from glibc {loader_version} but libc.so.6 is from glibc \
+                 {libc_version}; mixing them usually crashes at startup"
+            ),
+            Self::CopyFailed { path, cause } => {
+                write!(f, "failed to copy {}: {cause}", path.display())
+            }
+            Self::OwnerChange { path, cause } => {
+                write!(f, "Failed to set owner of {}: {cause}", path.display())
+            }
+            Self::ToolNotRun { tool, flag, cause } => {
+                write!(f, "Failed to run {tool} (needed by {flag}): {cause}")
+            }
+            Self::ToolFailed { tool, path } => write!(f, "{tool} failed on {}", path.display()),
+            Self::ChecksFailed(count) => write!(f, "{count} check(s) failed"),
+            Self::CachePublish(path) => write!(f, "Failed to publish cache {}", path.display()),
+            Self::Archive(msg) => write!(f, "Archive error: {msg}"),
+            Self::BundleCorrupt(msg) => write!(f, "Corrupt bundle: {msg}"),
+            Self::LoaderNotFound => write!(f, "No compatible loader found"),
+            Self::Launch(msg) => write!(f, "{msg}"),
+            Self::ProgramFailed { name, code } => write!(f, "'{name}' exited with code {code}"),
+            Self::HookFailed { hook, code } => {
+                write!(f, "post-extract hook '{hook}' failed with code {code}")
+            }
+            Self::Timeout(secs) => write!(f, "Timed out after {secs} seconds"),
+            Self::SizeLimit { what, size, limit } => write!(
+                f,
+                "Bundle {what} size {size} bytes exceeds --size-limit of {limit} bytes"
+            ),
+            Self::UnsupportedHost(os) => {
+                write!(f, "rex bundles are Linux-only; this host is {os}")
+            }
+        }
+    }
+}
+
+impl Error for RexError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(e)
+            | Self::OwnerChange { cause: e, .. }
+            | Self::ToolNotRun { cause: e, .. } => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RexError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
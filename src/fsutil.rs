@@ -0,0 +1,239 @@
+use std::env;
+use std::ffi::{CString, c_char, c_void};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+const EBUSY: i32 = 16;
+const ETXTBSY: i32 = 26;
+const BUSY_RETRIES: u32 = 5;
+const BUSY_BACKOFF: Duration = Duration::from_millis(60);
+const ENODATA: i32 = 61;
+const ENOTSUP: i32 = 95;
+const LOCK_SH: i32 = 1;
+const LOCK_EX: i32 = 2;
+const LOCK_NB: i32 = 4;
+const FICLONE: u64 = 0x4004_9409;
+const PROC_MOUNTS: &str = "/proc/self/mounts";
+
+unsafe extern "C" {
+    fn flock(fd: i32, operation: i32) -> i32;
+    fn ioctl(fd: i32, request: u64, ...) -> i32;
+    fn getxattr(path: *const c_char, name: *const c_char, value: *mut c_void, size: usize)
+    -> isize;
+    fn setxattr(
+        path: *const c_char,
+        name: *const c_char,
+        value: *const c_void,
+        size: usize,
+        flags: i32,
+    ) -> i32;
+}
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(io::Error::other)
+}
+
+/// Value of the extended attribute `name` of `path`, or `None` when it is not
+/// set or the filesystem has no xattr support.
+pub fn get_xattr(path: &Path, name: &str) -> io::Result<Option<Vec<u8>>> {
+    let (path, name) = (c_path(path)?, CString::new(name).map_err(io::Error::other)?);
+    let mut value = vec![0u8; 256];
+    let len = unsafe {
+        getxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_mut_ptr().cast(),
+            value.len(),
+        )
+    };
+    if len < 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(ENODATA | ENOTSUP) => Ok(None),
+            _ => Err(err),
+        };
+    }
+    value.truncate(len as usize);
+    Ok(Some(value))
+}
+
+pub fn set_xattr(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    let (path, name) = (c_path(path)?, CString::new(name).map_err(io::Error::other)?);
+    let ret = unsafe {
+        setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Runs `op`, retrying with exponential backoff (about 1s in total) while it
+/// fails with EBUSY/ETXTBSY, which some CI filesystems report briefly while a
+/// previous run's files are still being closed.
+pub fn retry_busy<T>(mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut delay = BUSY_BACKOFF;
+    for _ in 1..BUSY_RETRIES {
+        match op() {
+            Err(e) if matches!(e.raw_os_error(), Some(EBUSY | ETXTBSY)) => {
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            result => return result,
+        }
+    }
+    op()
+}
+
+/// Advisory `flock(2)` lock on a lock file, released when dropped. The
+/// holder of the exclusive lock may `remove` the file; whoever was waiting
+/// on it then notices it no longer is the file at the path and starts over.
+pub struct FileLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl FileLock {
+    /// Blocks until the exclusive lock on `path` (created if missing) is held.
+    pub fn exclusive(path: &Path) -> io::Result<Self> {
+        loop {
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(path)?;
+            let lock = Self {
+                file,
+                path: path.to_path_buf(),
+            };
+            lock.set(LOCK_EX)?;
+            if lock.is_current() {
+                return Ok(lock);
+            }
+        }
+    }
+
+    fn set(&self, operation: i32) -> io::Result<()> {
+        if unsafe { flock(self.file.as_raw_fd(), operation) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Whether the locked file is still the one at its path, i.e. was not
+    /// removed (and possibly recreated) by an earlier holder.
+    pub fn is_current(&self) -> bool {
+        match (self.file.metadata(), fs::metadata(&self.path)) {
+            (Ok(held), Ok(now)) => held.dev() == now.dev() && held.ino() == now.ino(),
+            _ => false,
+        }
+    }
+
+    /// Unlinks the lock file; only sound while holding the exclusive lock.
+    pub fn remove(&self) -> io::Result<()> {
+        fs::remove_file(&self.path)
+    }
+
+    /// Turns the lock into a shared one. Like `flock(2)` itself this is not
+    /// atomic: another process may briefly hold the exclusive lock in between.
+    pub fn downgrade(&self) -> io::Result<()> {
+        self.set(LOCK_SH)
+    }
+
+    /// Takes the exclusive lock only if no other process holds the file.
+    pub fn try_exclusive(&self) -> bool {
+        self.set(LOCK_EX | LOCK_NB).is_ok()
+    }
+}
+
+/// Copies `src` to `dest` as a reflink (`FICLONE`) where the filesystem
+/// supports it (btrfs, XFS, ...), so both share their blocks until either is
+/// modified, and falls back to a regular copy elsewhere. Returns the size.
+pub fn reflink_or_copy(src: &Path, dest: &Path) -> io::Result<u64> {
+    let source = File::open(src)?;
+    let target = File::create(dest)?;
+    if unsafe { ioctl(target.as_raw_fd(), FICLONE, source.as_raw_fd()) } == 0 {
+        return Ok(source.metadata()?.len());
+    }
+    fs::copy(src, dest)
+}
+
+/// Whether the filesystem holding `path` is mounted `noexec`, going by the
+/// longest matching mount point in `/proc/self/mounts`.
+pub fn is_noexec(path: &Path) -> io::Result<bool> {
+    let path = fs::canonicalize(path)?;
+    let mounts = fs::read_to_string(PROC_MOUNTS)?;
+    let mount = mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace().skip(1);
+            let point = fields.next()?.replace("\\040", " ");
+            let options = fields.nth(1)?;
+            path.starts_with(&point).then_some((point.len(), options))
+        })
+        .max_by_key(|(len, _)| *len);
+    Ok(mount.is_some_and(|(_, options)| options.split(',').any(|o| o == "noexec")))
+}
+
+/// Looks `name` up as an executable in `PATH`.
+pub fn which(name: &str) -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(name))
+        .find(|p| {
+            p.metadata()
+                .is_ok_and(|m| m.is_file() && m.mode() & 0o111 != 0)
+        })
+}
+
+/// Total size of the regular files under `path`, not following symlinks.
+pub fn tree_size(path: &Path) -> io::Result<u64> {
+    let meta = fs::symlink_metadata(path)?;
+    if !meta.is_dir() {
+        return Ok(if meta.is_file() { meta.len() } else { 0 });
+    }
+    let mut total = 0;
+    for entry in fs::read_dir(path)? {
+        total += tree_size(&entry?.path())?;
+    }
+    Ok(total)
+}
+
+pub fn remove_dir_all(path: &Path) -> io::Result<()> {
+    retry_busy(|| fs::remove_dir_all(path))
+}
+
+/// Overwrites every regular file under `path` with zeros and syncs it, so the
+/// following unlink does not leave the old contents in the freed blocks. This
+/// is best effort: copy-on-write filesystems and flash wear levelling may
+/// still keep the original data elsewhere.
+pub fn zero_files(path: &Path) -> io::Result<()> {
+    let meta = fs::symlink_metadata(path)?;
+    if meta.is_dir() {
+        for entry in fs::read_dir(path)? {
+            zero_files(&entry?.path())?;
+        }
+    } else if meta.is_file() {
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        let zeros = [0u8; 64 * 1024];
+        let mut left = meta.len();
+        while left > 0 {
+            let n = left.min(zeros.len() as u64) as usize;
+            file.write_all(&zeros[..n])?;
+            left -= n as u64;
+        }
+        file.sync_data()?;
+    }
+    Ok(())
+}
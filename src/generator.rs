@@ -1,204 +1,2611 @@
+use crate::archive::{self, HeaderOverrides, TarFormat};
+use crate::bundle::{
+    BundleMetadata, BundleOptions, CAPABILITY_XATTR, Codec, ENTRYPOINT_SCRIPT_NAME, EmbedMode,
+    FORMAT_VERSION, Fnv64, LAUNCHER_STAMP, MAGIC_MARKER, STDIN_FILE_NAME,
+};
+use crate::checksum::{Digest, Integrity};
+use crate::elf::{self, Elf};
+use crate::error::{Result, RexError};
+use crate::fsutil;
+use crate::log::{debug, info, warning};
+use crate::runtime::Runtime;
 use recursive_copy::{CopyOptions, copy_recursive};
 use rldd_rex::{ElfType, rldd_rex};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::env;
-use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
 use std::fs::{self, File, Permissions};
-use std::io::{self, Write};
+use std::io::{self, IsTerminal, Read, Seek, SeekFrom, Write};
 use std::mem::size_of;
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt, lchown, symlink};
 use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 use zstd::stream::write::Encoder;
 
-const MAGIC_MARKER: [u8; 10] = *b"REX_BUNDLE";
+const BENCHMARK_LEVELS: &[i32] = &[3, 9, 15, 19];
+const DEFAULT_EXTENSION: &str = ".Rex";
+const SLOW_LEVEL: i32 = 19;
+const SLOW_INPUT_SIZE: u64 = 100 << 20;
+const TUNE_SAMPLE_SIZE: u64 = 16 << 20;
+const TUNE_LEVELS: (i32, i32) = (3, 19);
+const INTERPRETER_DIR: &str = ".interp";
+const INTERPRETER_PREFIX: &str = "rex-";
 
-#[repr(C, packed)]
-struct BundleMetadata {
-    payload_size: u64,
-    target_bin_name_len: u32,
+const COMPRESSED_MAGICS: &[&[u8]] = &[
+    b"\x1f\x8b",
+    b"\x28\xb5\x2f\xfd",
+    b"\xfd7zXZ\x00",
+    b"BZh",
+    b"PK\x03\x04",
+    b"7z\xbc\xaf\x27\x1c",
+    b"\x89PNG",
+    b"\xff\xd8\xff",
+    b"GIF8",
+    b"OggS",
+    b"\x1a\x45\xdf\xa3",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Rex,
+    Rootfs,
+}
+
+impl OutputFormat {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "rex" => Ok(Self::Rex),
+            "rootfs" => Ok(Self::Rootfs),
+            _ => Err(RexError::Usage(format!(
+                "Unknown output format '{value}' (expected rex or rootfs)"
+            ))),
+        }
+    }
+}
+
+/// Which size `--size-limit` is checked against.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SizeLimitOn {
+    Compressed,
+    Uncompressed,
+}
+
+impl SizeLimitOn {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "compressed" => Ok(Self::Compressed),
+            "uncompressed" => Ok(Self::Uncompressed),
+            _ => Err(RexError::Usage(format!(
+                "Unknown --size-limit-on '{value}' (expected compressed or uncompressed)"
+            ))),
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Self::Compressed => "compressed",
+            Self::Uncompressed => "uncompressed",
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct BundleArgs {
     pub target_binary: PathBuf,
     pub compression_level: i32,
+    /// Whether the level came from -L or the environment rather than the
+    /// default; edits otherwise keep the level the bundle was built with.
+    pub level_explicit: bool,
     pub extra_libs: Vec<PathBuf>,
     pub additional_files: Vec<String>,
     pub extra_bins: Vec<PathBuf>,
+    pub stdin_file: Option<PathBuf>,
+    pub entrypoint_script: Option<PathBuf>,
+    pub timeout: Option<u64>,
+    pub verify_after_build: bool,
+    pub store_patterns: Vec<String>,
+    pub store_compressed: bool,
+    pub files_base: Option<PathBuf>,
+    pub cache: bool,
+    pub no_path_prepend: bool,
+    pub min_compression_savings: Option<f64>,
+    pub resolve_target_name: bool,
+    pub target_name: Option<String>,
+    pub output_format: OutputFormat,
+    pub preserve_hardlinks: bool,
+    pub secure_cleanup: bool,
+    pub clean_env: bool,
+    pub clean_env_prefixes: Vec<String>,
+    pub keep_env: Vec<String>,
+    pub relink_rpath: bool,
+    pub no_loader_wrapper: bool,
+    pub stats_out: Option<PathBuf>,
+    pub overrides: HeaderOverrides,
+    pub tar_format: TarFormat,
+    pub follow_interpreter: bool,
+    pub benchmark: bool,
+    pub json: bool,
+    pub prune_unused_libs: bool,
+    pub strict_deps: bool,
+    pub output: Option<PathBuf>,
+    pub extension: Option<String>,
+    pub keep_payload: Option<PathBuf>,
+    pub dereference_data: bool,
+    pub strip: bool,
+    pub include_debug: bool,
+    /// `--integrity`, if given; edits otherwise keep the bundle's mode and new
+    /// builds use the default.
+    pub integrity: Option<Integrity>,
+    pub no_recreate: bool,
+    pub post_extract: Option<String>,
+    pub install_dir: Option<String>,
+    pub install_command: Option<String>,
+    pub pidfile: Option<String>,
+    pub prepend_args: Vec<String>,
+    pub loader_args: Vec<String>,
+    pub toolbox: Option<PathBuf>,
+    pub dedup_store: Option<PathBuf>,
+    pub check_only: bool,
+    pub sysroot: Option<PathBuf>,
+    pub max_deps: Option<usize>,
+    pub min_glibc: Option<String>,
+    pub size_limit: Option<u64>,
+    pub size_limit_on: SizeLimitOn,
+    /// `--embed-mode`, if given; edits otherwise keep the bundle's mode and
+    /// new builds append.
+    pub embed_mode: Option<EmbedMode>,
+    pub no_extra_libs_deps: bool,
+    pub time_budget: Option<u64>,
 }
 
 fn recreate_dir(path: &Path) -> io::Result<()> {
     if path.exists() {
-        fs::remove_dir_all(path)?;
+        fsutil::remove_dir_all(path)?;
+    }
+    fsutil::retry_busy(|| fs::create_dir_all(path))
+}
+
+fn same_contents(a: &Path, b: &Path) -> bool {
+    let (Ok(meta_a), Ok(meta_b)) = (fs::metadata(a), fs::metadata(b)) else {
+        return false;
+    };
+    let (Ok(mut file_a), Ok(mut file_b)) = (File::open(a), File::open(b)) else {
+        return false;
+    };
+    if meta_a.len() != meta_b.len() {
+        return false;
+    }
+    let mut buf_a = vec![0u8; 64 * 1024];
+    let mut buf_b = vec![0u8; 64 * 1024];
+    loop {
+        let n = match file_a.read(&mut buf_a) {
+            Ok(0) => return true,
+            Ok(n) => n,
+            Err(_) => return false,
+        };
+        if file_b.read_exact(&mut buf_b[..n]).is_err() || buf_a[..n] != buf_b[..n] {
+            return false;
+        }
+    }
+}
+
+// Leaves an identical, already staged file alone, which keeps its timestamps
+// in staging trees reused with --no-recreate.
+fn copy_file(src: &Path, dest: &Path) -> io::Result<()> {
+    if same_contents(src, dest) {
+        debug!("stage {}: unchanged", dest.display());
+    } else {
+        debug!("stage {} -> {}", src.display(), dest.display());
+        fs::copy(src, dest)?;
+    }
+    Ok(())
+}
+
+type HardlinkMap = HashMap<(u64, u64), PathBuf>;
+
+fn copy_tree(
+    src: &Path,
+    dest: &Path,
+    follow_symlinks: bool,
+    hardlinks: Option<&mut HardlinkMap>,
+) -> io::Result<()> {
+    copy_tree_at(src, dest, follow_symlinks, &mut HashSet::new(), hardlinks)
+}
+
+// `ancestors` holds the (device, inode) pairs of the directories currently
+// being copied, so a symlink pointing back up the tree is skipped instead of
+// recursing forever. With `hardlinks`, files sharing an inode are linked to
+// their first staged copy instead of being copied again.
+fn copy_tree_at(
+    src: &Path,
+    dest: &Path,
+    follow_symlinks: bool,
+    ancestors: &mut HashSet<(u64, u64)>,
+    mut hardlinks: Option<&mut HardlinkMap>,
+) -> io::Result<()> {
+    let meta = if follow_symlinks {
+        fs::metadata(src)
+    } else {
+        fs::symlink_metadata(src)
+    };
+    let meta = match meta {
+        Ok(m) => m,
+        Err(e) => {
+            warning!("[Warning] Skipping {}: {e}", src.display());
+            return Ok(());
+        }
+    };
+
+    if meta.file_type().is_symlink() {
+        if dest.symlink_metadata().is_ok() {
+            fs::remove_file(dest)?;
+        }
+        return symlink(fs::read_link(src)?, dest);
+    }
+
+    let id = (meta.dev(), meta.ino());
+    if !meta.is_dir() {
+        if let Some(links) = hardlinks.filter(|_| meta.nlink() > 1) {
+            if let Some(first) = links.get(&id) {
+                if dest.symlink_metadata().is_ok() {
+                    fs::remove_file(dest)?;
+                }
+                return fs::hard_link(first, dest);
+            }
+            links.insert(id, dest.to_path_buf());
+        }
+        return copy_file(src, dest);
+    }
+
+    if !ancestors.insert(id) {
+        warning!("[Warning] Skipping symlink cycle: {}", src.display());
+        return Ok(());
+    }
+
+    fs::create_dir_all(dest)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        copy_tree_at(
+            &entry.path(),
+            &dest.join(entry.file_name()),
+            follow_symlinks,
+            ancestors,
+            hardlinks.as_deref_mut(),
+        )?;
+    }
+
+    ancestors.remove(&id);
+    Ok(())
+}
+
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.first(), text.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_match(&pattern[1..], text) || (!text.is_empty() && glob_match(pattern, &text[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob_match(&pattern[1..], &text[1..]),
+        (Some(p), Some(t)) if p == t => glob_match(&pattern[1..], &text[1..]),
+        _ => false,
+    }
+}
+
+fn looks_compressed(path: &Path) -> bool {
+    let mut head = [0u8; 12];
+    let Ok(n) = File::open(path).and_then(|mut f| f.read(&mut head)) else {
+        return false;
+    };
+    let head = &head[..n];
+    COMPRESSED_MAGICS.iter().any(|m| head.starts_with(m))
+        || head.get(4..8) == Some(b"ftyp".as_slice())
+}
+
+fn is_stored(path: &Path, rel: &Path, args: &BundleArgs) -> bool {
+    let name = path.file_name().unwrap_or_default().as_encoded_bytes();
+    let rel = rel.as_os_str().as_encoded_bytes();
+    args.store_patterns
+        .iter()
+        .any(|p| glob_match(p.as_bytes(), name) || glob_match(p.as_bytes(), rel))
+        || (args.store_compressed && looks_compressed(path))
+}
+
+// Moves additional files that should skip compression from the staging tree
+// into `store_dir`, keeping their relative layout so both archives unpack
+// into the same bundle directory.
+fn move_stored_files(
+    path: &Path,
+    staging_dir: &Path,
+    store_dir: &Path,
+    args: &BundleArgs,
+) -> io::Result<usize> {
+    let meta = fs::symlink_metadata(path)?;
+    if meta.is_dir() {
+        let mut moved = 0;
+        for entry in fs::read_dir(path)? {
+            moved += move_stored_files(&entry?.path(), staging_dir, store_dir, args)?;
+        }
+        return Ok(moved);
+    }
+
+    let rel = path.strip_prefix(staging_dir).unwrap_or(path);
+    if !meta.is_file() || !is_stored(path, rel, args) {
+        return Ok(0);
+    }
+
+    let dest = store_dir.join(rel);
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(path, &dest)?;
+    Ok(1)
+}
+
+type Deps = Vec<(String, PathBuf)>;
+
+// The target is the first file a build touches, so failures to read it say
+// which path was meant and what to check rather than a bare OS error.
+fn open_target(path: &Path) -> Result<PathBuf> {
+    let target = fs::canonicalize(path).map_err(|e| target_error(path, &e))?;
+    ensure_elf(&target)?;
+    Ok(target)
+}
+
+fn target_error(path: &Path, e: &io::Error) -> RexError {
+    let cause = match e.kind() {
+        io::ErrorKind::NotFound => "file not found; check the -t path".to_string(),
+        io::ErrorKind::PermissionDenied => "file is not readable (permission denied)".to_string(),
+        io::ErrorKind::IsADirectory => "is a directory, not an executable".to_string(),
+        _ => e.to_string(),
+    };
+    RexError::Resolve {
+        path: path.to_path_buf(),
+        cause,
     }
-    fs::create_dir_all(path)
 }
 
-fn collect_deps(path: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
-    let deps = rldd_rex(path)?;
+fn ensure_elf(path: &Path) -> Result<()> {
+    match elf::check_header(path).map_err(|e| target_error(path, &e))? {
+        Some(reason) => Err(RexError::NotElf {
+            path: path.to_path_buf(),
+            reason,
+        }),
+        None => Ok(()),
+    }
+}
+
+// Returns each dependency's name and resolved path, or `None` for
+// static/invalid ELFs.
+fn resolve_deps(path: &Path) -> Result<Option<Deps>> {
+    let deps = rldd_rex(path).map_err(|e| RexError::Resolve {
+        path: path.to_path_buf(),
+        cause: e.to_string(),
+    })?;
     if matches!(deps.elf_type, ElfType::Invalid | ElfType::Static) {
-        return Ok(vec![]);
+        debug!("resolve {}: not a dynamic ELF", path.display());
+        return Ok(None);
     }
-    Ok(deps
+    for (name, dep) in &deps.deps {
+        debug!("resolve {}: {name} => {dep}", path.display());
+    }
+    let mut deps: Deps = deps
         .deps
         .iter()
-        .map(|(_, p)| PathBuf::from(p))
+        .map(|(n, p)| (n.to_string(), PathBuf::from(p)))
+        .collect();
+    resolve_dst_deps(path, &mut deps);
+    Ok(Some(deps))
+}
+
+// rldd does not expand `$ORIGIN`, `$LIB` and `$PLATFORM` in RUNPATH/RPATH,
+// so private libraries next to the binary (`$ORIGIN/lib`) come back as
+// missing. Those are looked up again in the expanded search path of the
+// object needing them, and the deps of every library found that way are
+// merged in.
+fn resolve_dst_deps(path: &Path, deps: &mut Deps) {
+    let mut queue = vec![path.to_path_buf()];
+    let mut seen = HashSet::new();
+    while let Some(owner) = queue.pop() {
+        if !seen.insert(owner.clone()) {
+            continue;
+        }
+        let Ok(elf) = Elf::open(&owner) else {
+            continue;
+        };
+        let dirs = elf.search_path(&owner);
+        if dirs.is_empty() {
+            continue;
+        }
+        for name in elf.needed() {
+            let Some(found) = dirs.iter().map(|d| d.join(&name)).find(|p| p.is_file()) else {
+                continue;
+            };
+            match deps.iter_mut().find(|(n, _)| *n == name) {
+                Some((_, dep)) if dep.exists() => continue,
+                Some((_, dep)) => *dep = found.clone(),
+                None => deps.push((name.clone(), found.clone())),
+            }
+            debug!(
+                "resolve {}: {name} => {} (expanded RUNPATH)",
+                path.display(),
+                found.display()
+            );
+            if let Ok(more) = rldd_rex(&found) {
+                for (n, p) in &more.deps {
+                    if !deps.iter().any(|(m, _)| m == n) {
+                        deps.push((n.to_string(), PathBuf::from(p)));
+                    }
+                }
+            }
+            queue.push(found);
+        }
+    }
+}
+
+// With `--sysroot` nothing may come from the host, so instead of rldd the
+// `DT_NEEDED` entries are looked up in the object's RUNPATH and the default
+// directories, all re-rooted under the sysroot. The program interpreter is
+// added as a dependency so the loader is staged with it.
+fn resolve_sysroot_deps(path: &Path, sysroot: &Path) -> Result<Option<Deps>> {
+    let elf = Elf::open(path).map_err(|e| RexError::Resolve {
+        path: path.to_path_buf(),
+        cause: e.to_string(),
+    })?;
+    let interp = elf.interpreter();
+    if interp.is_none() && elf.needed().is_empty() {
+        debug!("resolve {}: not a dynamic ELF", path.display());
+        return Ok(None);
+    }
+    let default_dirs = sysroot_lib_dirs(sysroot, interp.as_deref(), elf.arch());
+    let mut deps: Deps = vec![];
+    let mut queue = vec![path.to_path_buf()];
+    let mut seen = HashSet::new();
+    while let Some(owner) = queue.pop() {
+        if !seen.insert(owner.clone()) {
+            continue;
+        }
+        let Ok(elf) = Elf::open(&owner) else {
+            continue;
+        };
+        let runpath = elf.search_path(&owner).into_iter().map(|dir| {
+            if is_within(&dir, sysroot) {
+                dir
+            } else {
+                sysroot.join(dir.strip_prefix("/").unwrap_or(&dir))
+            }
+        });
+        let dirs: Vec<PathBuf> = runpath.chain(default_dirs.iter().cloned()).collect();
+        for name in elf.needed() {
+            if deps.iter().any(|(n, _)| *n == name) {
+                continue;
+            }
+            match dirs.iter().map(|d| d.join(&name)).find(|p| p.is_file()) {
+                Some(found) => {
+                    debug!("resolve {}: {name} => {}", owner.display(), found.display());
+                    deps.push((name, found.clone()));
+                    queue.push(found);
+                }
+                None => deps.push((name, PathBuf::from("not found"))),
+            }
+        }
+    }
+    if let Some(interp) = interp {
+        let loader = sysroot.join(interp.trim_start_matches('/'));
+        let name = loader
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        if !deps.iter().any(|(n, _)| *n == name) {
+            deps.push((name, loader));
+        }
+    }
+    Ok(Some(deps))
+}
+
+// musl reads its search path from `/etc/ld-musl-<arch>.path` when present;
+// otherwise (and for glibc) the usual library directories apply, preceded by
+// the Debian-style multiarch ones.
+fn sysroot_lib_dirs(sysroot: &Path, interp: Option<&str>, arch: Option<&str>) -> Vec<PathBuf> {
+    let musl_arch = interp
+        .and_then(|i| Path::new(i).file_name()?.to_str())
+        .and_then(|name| name.strip_prefix("ld-musl-")?.strip_suffix(".so.1"));
+    if let Some(arch) = musl_arch
+        && let Ok(text) = fs::read_to_string(sysroot.join(format!("etc/ld-musl-{arch}.path")))
+    {
+        return text
+            .split([':', '\n'])
+            .map(str::trim)
+            .filter(|dir| !dir.is_empty())
+            .map(|dir| sysroot.join(dir.trim_start_matches('/')))
+            .collect();
+    }
+    let multiarch = match arch {
+        Some("x86") => Some("i386-linux-gnu".to_string()),
+        Some("arm") => Some("arm-linux-gnueabihf".to_string()),
+        Some(arch) => Some(format!("{arch}-linux-gnu")),
+        None => None,
+    };
+    let multiarch_dirs = multiarch
+        .iter()
+        .flat_map(|triplet| [format!("lib/{triplet}"), format!("usr/lib/{triplet}")]);
+    let plain_dirs = ["lib64", "usr/lib64", "lib", "usr/lib", "usr/local/lib"].map(String::from);
+    multiarch_dirs
+        .chain(plain_dirs)
+        .map(|dir| sysroot.join(dir))
+        .collect()
+}
+
+// The target must have been linked for the libc the sysroot provides: its
+// interpreter has to exist there, and a musl loader in one with a glibc
+// loader in the other (or vice versa) means the wrong sysroot was given.
+fn check_sysroot(target: &Path, sysroot: &Path) -> Result<()> {
+    if !sysroot.is_dir() {
+        return Err(RexError::Usage(format!(
+            "Error: --sysroot {} is not a directory",
+            sysroot.display()
+        )));
+    }
+    let Some(interp) = Elf::open(target)?.interpreter() else {
+        return Err(RexError::NotSharedElf(target.to_path_buf()));
+    };
+    let is_musl = |name: &str| name.starts_with("ld-musl");
+    let interp_name = Path::new(&interp)
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    let sysroot_musl = fs::read_dir(sysroot.join("lib"))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .any(|e| is_musl(&e.file_name().to_string_lossy()));
+    if is_musl(&interp_name) != sysroot_musl {
+        let family = |musl| if musl { "musl" } else { "glibc" };
+        return Err(RexError::Usage(format!(
+            "Error: {} is linked against {} ({interp}), but --sysroot {} is a {} sysroot",
+            target.display(),
+            family(is_musl(&interp_name)),
+            sysroot.display(),
+            family(sysroot_musl)
+        )));
+    }
+    if !sysroot.join(interp.trim_start_matches('/')).exists() {
+        return Err(RexError::Usage(format!(
+            "Error: interpreter {interp} of {} is missing from --sysroot {}",
+            target.display(),
+            sysroot.display()
+        )));
+    }
+    Ok(())
+}
+
+// Resolves the deps of every path on a small thread pool, since rldd walks
+// the library search path for each one. Results come back in input order so
+// the staged set and the warnings stay deterministic.
+fn resolve_all(paths: &[PathBuf], sysroot: Option<&Path>) -> Vec<Result<Option<Deps>>> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(paths.len()));
+    let workers = thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(paths.len());
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(path) = paths.get(index) else {
+                        break;
+                    };
+                    let deps = match sysroot {
+                        Some(sysroot) => resolve_sysroot_deps(path, sysroot),
+                        None => resolve_deps(path),
+                    };
+                    results
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .push((index, deps));
+                }
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap_or_else(|e| e.into_inner());
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, deps)| deps).collect()
+}
+
+fn collect_deps(path: &Path) -> Result<Vec<PathBuf>> {
+    let deps = resolve_deps(path)?.unwrap_or_default();
+    Ok(deps
+        .into_iter()
+        .map(|(_, p)| p)
         .filter(|p| p.exists())
         .collect())
 }
 
-fn create_payload(path: &Path, target: &str, level: i32) -> Result<PathBuf, Box<dyn Error>> {
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+pub fn list_deps(target: &Path, json: bool) -> Result<()> {
+    let target = open_target(target)?;
+    let deps = resolve_deps(&target)?.ok_or_else(|| RexError::NotSharedElf(target.clone()))?;
+
+    if json {
+        let entries: Vec<String> = deps
+            .iter()
+            .map(|(name, path)| {
+                format!(
+                    "{{\"name\":{},\"path\":{},\"found\":{}}}",
+                    json_string(name),
+                    json_string(&path.to_string_lossy()),
+                    path.exists()
+                )
+            })
+            .collect();
+        println!(
+            "{{\"target\":{},\"deps\":[{}]}}",
+            json_string(&target.to_string_lossy()),
+            entries.join(",")
+        );
+        return Ok(());
+    }
+
+    println!("[Deps] {} ({} dependencies)", target.display(), deps.len());
+    for (name, path) in &deps {
+        if path.exists() {
+            println!("  {name} => {}", path.display());
+        } else {
+            println!("  {name} => not found");
+        }
+    }
+    Ok(())
+}
+
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn create_payload(
+    path: &Path,
+    target: &str,
+    level: i32,
+    overrides: HeaderOverrides,
+    format: TarFormat,
+) -> Result<(PathBuf, u64)> {
     let tmp = env::temp_dir().join(format!("{target}_bundle_tmp"));
     recreate_dir(&tmp)?;
 
     let pay = tmp.join(format!("{target}.tar.zstd"));
-    println!("[Packaging] Creating TAR+ZSTD (level {level})");
+    let level = Codec::Zstd.clamp_level(level);
+    info!("[Packaging] Creating TAR+ZSTD (level {level})");
 
     let file = File::create(&pay)?;
     let mut enc = Encoder::new(file, level)?;
     enc.long_distance_matching(true)?;
     let mut encoder = enc.auto_finish();
 
-    let mut builder = tar_minimal::Builder::new(&mut encoder);
+    let mut counter = CountingWriter {
+        inner: &mut encoder,
+        count: 0,
+    };
+    let mut builder = archive::Writer::new(&mut counter, overrides, format);
     builder.append_dir_all(&format!("{target}_bundle"), path)?;
-    Ok(pay)
+    builder.finish()?;
+    Ok((pay, counter.count))
 }
 
-fn copy_bin_and_deps(file: &Path, bin_dir: &Path, libs_dir: &Path) -> Result<(), Box<dyn Error>> {
-    let dest = bin_dir.join(file.file_name().unwrap_or_default());
-    fs::copy(file, &dest)?;
-    println!("[Staging] Copied binary: {}", dest.display());
+fn create_store(
+    path: &Path,
+    target: &str,
+    overrides: HeaderOverrides,
+    format: TarFormat,
+) -> Result<PathBuf> {
+    let store = env::temp_dir()
+        .join(format!("{target}_bundle_tmp"))
+        .join(format!("{target}.tar"));
+    info!("[Packaging] Creating uncompressed TAR for stored files");
 
-    let mut coptions = CopyOptions::default();
-    coptions.content_only = true;
-    coptions.follow_symlinks = true;
-    for dep in collect_deps(file)? {
-        copy_recursive(&dep, libs_dir, &coptions).ok();
-    }
-    Ok(())
+    let mut builder = archive::Writer::new(File::create(&store)?, overrides, format);
+    builder.append_dir_all(&format!("{target}_bundle"), path)?;
+    builder.finish()?;
+    Ok(store)
 }
 
-pub fn generate_bundle(args: BundleArgs) -> Result<(), Box<dyn Error>> {
-    let target = &args.target_binary;
-    let deps = rldd_rex(target)?;
+// Splits a `#!` line into the interpreter to bundle and its arguments,
+// looking through `/usr/bin/env [-S] prog`.
+fn parse_shebang(line: &str) -> Option<(PathBuf, String)> {
+    let mut words = line.strip_prefix("#!")?.split_whitespace();
+    let interp = words.next()?;
+    let mut rest: Vec<&str> = words.collect();
+    if Path::new(interp).file_name()? != "env" {
+        return Some((PathBuf::from(interp), rest.join(" ")));
+    }
+    if rest.first() == Some(&"-S") {
+        rest.remove(0);
+    }
+    let prog = rest.first()?;
+    Some((fsutil::which(prog)?, rest[1..].join(" ")))
+}
 
-    if matches!(deps.elf_type, ElfType::Invalid | ElfType::Static) {
-        return Err("Not Shared ELF binary".into());
+fn script_files(path: &Path, out: &mut Vec<PathBuf>) -> io::Result<()> {
+    let meta = fs::symlink_metadata(path)?;
+    if meta.is_dir() {
+        for entry in fs::read_dir(path)? {
+            script_files(&entry?.path(), out)?;
+        }
+    } else if meta.is_file() {
+        let mut head = [0u8; 2];
+        if File::open(path)?.read_exact(&mut head).is_ok() && &head == b"#!" {
+            out.push(path.to_path_buf());
+        }
     }
+    Ok(())
+}
 
-    let target_name = target.file_name().unwrap().to_str().ok_or("Invalid UTF-8")?;
-    let staging_dir = env::temp_dir().join(format!("{target_name}_bundle"));
+// Stages the interpreter of every script among `extra_dests` into
+// `bins/.interp/` (and its deps into libs/) behind a `bins/rex-<name>`
+// wrapper that starts it with the bundled loader and libraries. The shebang
+// names the wrapper through `env`; the `rex-` prefix keeps a host program
+// from shadowing it even with `--no-path-prepend`. Returns the newly staged
+// libraries.
+fn follow_interpreters(
+    extra_dests: &[PathBuf],
+    bin_dir: &Path,
+    libs_dir: &Path,
+    strict: bool,
+) -> Result<Vec<PathBuf>> {
+    let mut scripts = vec![];
+    for dest in extra_dests.iter().filter(|d| d.symlink_metadata().is_ok()) {
+        script_files(dest, &mut scripts)?;
+    }
 
-    recreate_dir(&staging_dir)?;
-    let bin_dir = staging_dir.join("bins");
-    let libs_dir = staging_dir.join("libs");
-    fs::create_dir_all(&bin_dir)?;
-    fs::create_dir_all(&libs_dir)?;
+    let coptions = CopyOptions {
+        content_only: true,
+        follow_symlinks: true,
+        ..Default::default()
+    };
+    let mut staged = vec![];
+    for script in scripts {
+        let content = fs::read(&script)?;
+        let end = content
+            .iter()
+            .position(|b| *b == b'\n')
+            .unwrap_or(content.len());
+        let line = String::from_utf8_lossy(&content[..end]);
+        let Some((interp, interp_args)) = parse_shebang(&line) else {
+            warning!(
+                "[Warning] Cannot resolve interpreter of {}: {line}",
+                script.display()
+            );
+            continue;
+        };
+        let name = interp.file_name().unwrap_or_default().to_string_lossy();
+        let name = format!("{INTERPRETER_PREFIX}{name}");
+        let wrapper = bin_dir.join(&name);
+        if !wrapper.exists() {
+            info!(
+                "[Staging] Bundling interpreter {} for {}",
+                interp.display(),
+                script.display()
+            );
+            let real = fs::canonicalize(&interp)?;
+            let mut deps = collect_deps(&real)?;
+            let loader = Elf::open(&real)?.interpreter().map(PathBuf::from);
+            deps.extend(loader.iter().filter_map(|l| fs::canonicalize(l).ok()));
+            for dep in deps {
+                if !libs_dir.join(dep.file_name().unwrap_or_default()).exists() {
+                    check_copy(copy_recursive(&dep, libs_dir, &coptions), &dep, strict)?;
+                    staged.push(dep);
+                }
+            }
+            stage_interpreter(&interp, loader.as_deref(), bin_dir, &wrapper)?;
+        }
 
-    let cwd = env::current_dir()?;
-    let mut coptions = CopyOptions::default();
+        let shebang = if interp_args.is_empty() {
+            format!("#!/usr/bin/env {name}")
+        } else {
+            format!("#!/usr/bin/env -S {name} {interp_args}")
+        };
+        let mut rewritten = shebang.into_bytes();
+        rewritten.extend_from_slice(&content[end..]);
+        fs::write(&script, rewritten)?;
+    }
+    Ok(staged)
+}
 
-    let libs: Vec<PathBuf> = deps
-        .deps
-        .iter()
-        .map(|(_, p)| PathBuf::from(p))
-        .filter(|p| p.exists())
-        .collect();
+// Copies `interp` into `bins/.interp/` and writes its `wrapper`, which runs
+// it through the bundled copy of `loader` (named after its PT_INTERP) and
+// libs/, wherever the bundle was extracted. Static interpreters are started
+// directly.
+fn stage_interpreter(
+    interp: &Path,
+    loader: Option<&Path>,
+    bin_dir: &Path,
+    wrapper: &Path,
+) -> Result<()> {
+    let name = interp.file_name().unwrap_or_default().to_string_lossy();
+    let real_dir = bin_dir.join(INTERPRETER_DIR);
+    fs::create_dir_all(&real_dir)?;
+    fs::copy(interp, real_dir.join(name.as_ref()))?;
 
-    println!("[Staging] Copying target binary: {}", target.display());
-    fs::copy(target, staging_dir.join(target_name))?;
+    let run = format!("\"$bins/{INTERPRETER_DIR}/{name}\" \"$@\"");
+    let exec = match loader.and_then(|l| l.file_name()) {
+        Some(loader) => format!(
+            "exec \"$bins/../libs/{}\" --library-path \"$bins/../libs\" {run}",
+            loader.to_string_lossy()
+        ),
+        None => format!("exec {run}"),
+    };
+    fs::write(
+        wrapper,
+        format!("#!/bin/sh\n# Bundled {name} (--follow-interpreter)\nbins=${{0%/*}}\n{exec}\n"),
+    )?;
+    fs::set_permissions(wrapper, Permissions::from_mode(0o755))?;
+    Ok(())
+}
 
-    if !args.extra_bins.is_empty() {
-        println!(
-            "[Staging] Processing {} extra binaries...",
-            args.extra_bins.len()
-        );
-        for entry in &args.extra_bins {
-            if entry.is_dir() {
-                for f in fs::read_dir(entry)? {
-                    let path = f?.path();
-                    if path.is_file() {
-                        copy_bin_and_deps(&path, &bin_dir, &libs_dir)?;
-                    }
+// Expands directories given to -b/-l into the regular files they contain.
+fn expand_entries(entries: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in entries {
+        if entry.is_dir() {
+            for f in fs::read_dir(entry)? {
+                let path = f?.path();
+                if path.is_file() {
+                    files.push(path);
                 }
-            } else {
-                copy_bin_and_deps(entry, &bin_dir, &libs_dir)?;
             }
+        } else {
+            files.push(entry.clone());
         }
     }
+    Ok(files)
+}
 
-    println!("[Staging] Copying {} shared libs...", libs.len());
-    for lib in &libs {
-        coptions.content_only = true;
-        coptions.follow_symlinks = true;
-        copy_recursive(lib, &libs_dir, &coptions).ok();
-    }
-
-    if !args.extra_libs.is_empty() {
-        println!("[Staging] Copying {} extra libs...", args.extra_libs.len());
-        for entry in &args.extra_libs {
-            coptions.follow_symlinks = false;
-            if entry.is_dir() {
-                for f in fs::read_dir(entry)? {
-                    let p = f?.path();
-                    if p.is_file() {
-                        copy_recursive(&p, &libs_dir, &coptions).ok();
-                    }
-                }
-            } else {
-                copy_recursive(entry, &libs_dir, &coptions).ok();
-            }
+// The rootfs counterpart of the TAR header overrides. Changing ownership
+// needs root; failing to do so is an error rather than a silently
+// user-owned tree.
+fn apply_overrides(path: &Path, overrides: HeaderOverrides) -> Result<()> {
+    let meta = fs::symlink_metadata(path)?;
+    if let Some((uid, gid)) = overrides.owner
+        && let Err(e) = lchown(path, Some(uid), Some(gid))
+    {
+        return Err(RexError::OwnerChange {
+            path: path.to_path_buf(),
+            cause: e,
+        });
+    }
+    if meta.file_type().is_symlink() {
+        return Ok(());
+    }
+    if overrides.mode.is_some() && !elf::is_loader(path) {
+        let exec = meta.is_dir() || meta.mode() & 0o111 != 0;
+        let mode = overrides.mode(meta.mode() & 0o7777, exec);
+        fs::set_permissions(path, Permissions::from_mode(mode))?;
+    }
+    if meta.is_dir() {
+        for entry in fs::read_dir(path)? {
+            apply_overrides(&entry?.path(), overrides)?;
         }
     }
+    Ok(())
+}
 
-    for extra in &args.additional_files {
-        coptions.content_only = false;
-        let path = cwd.join(extra);
-        if path.is_dir() {
-            let parent_name = path
-                .file_name()
-                .and_then(|n| n.to_str())
-                .unwrap_or_default();
-            let dest = staging_dir.join(parent_name);
-            recreate_dir(&dest)?;
-            println!("[Staging] Copying directory: {}", path.display());
-            copy_recursive(&path, &dest, &coptions).ok();
+// Lays the staged tree out as a chroot-able filesystem: executables in
+// `/bin`, each library back in the directory it was resolved from (so the
+// `PT_INTERP` path and the loader's default search paths keep working), and
+// everything else at the root.
+fn build_rootfs(
+    staging_dir: &Path,
+    rootfs: &Path,
+    target_name: &str,
+    lib_sources: &HashMap<OsString, PathBuf>,
+    interp: Option<String>,
+) -> Result<()> {
+    recreate_dir(rootfs)?;
+    let bin = rootfs.join("bin");
+    fs::create_dir_all(&bin)?;
+
+    for entry in fs::read_dir(staging_dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == "bins" || name == "libs" {
+            continue;
+        }
+        let dest = if name == target_name {
+            bin.join(&name)
         } else {
-            println!("[Staging] Copying file: {}", path.display());
-            copy_recursive(&path, &staging_dir, &coptions).ok();
+            rootfs.join(&name)
+        };
+        copy_tree(&entry.path(), &dest, false, None)?;
+    }
+
+    for entry in fs::read_dir(staging_dir.join("bins"))? {
+        let entry = entry?;
+        copy_tree(&entry.path(), &bin.join(entry.file_name()), false, None)?;
+    }
+
+    for entry in fs::read_dir(staging_dir.join("libs"))? {
+        let entry = entry?;
+        let dir = match lib_sources.get(&entry.file_name()) {
+            Some(dir) => rootfs.join(dir.strip_prefix("/").unwrap_or(dir)),
+            None => rootfs.join("lib"),
+        };
+        fs::create_dir_all(&dir)?;
+        copy_tree(&entry.path(), &dir.join(entry.file_name()), false, None)?;
+    }
+
+    if let Some(interp) = interp {
+        let dest = rootfs.join(interp.trim_start_matches('/'));
+        let src = staging_dir
+            .join("libs")
+            .join(Path::new(&interp).file_name().unwrap_or_default());
+        if !dest.exists() && src.exists() {
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            copy_tree(&src, &dest, true, None)?;
         }
     }
+    Ok(())
+}
 
-    let payload = create_payload(&staging_dir, target_name, args.compression_level)?;
-    let payload_size = payload.metadata()?.len();
-    let output = format!("{target_name}.Rex",);
+// Where a `-f` entry is read from: `~` and `~/...` are expanded from $HOME
+// (the shell leaves `~` alone in quotes, `--opt=~/x` forms and manifests),
+// absolute paths are taken as they are and relative ones are resolved
+// against --files-base, by default the current directory.
+fn additional_file_path(files_base: &Path, extra: &str) -> PathBuf {
+    expand_file_path(files_base, extra, env::var_os("HOME"))
+}
 
-    println!("[Output] Creating bundle: {output}");
-    fs::copy(env::current_exe()?, &output)?;
-    fs::set_permissions(&output, Permissions::from_mode(0o755))?;
+fn expand_file_path(files_base: &Path, extra: &str, home: Option<OsString>) -> PathBuf {
+    if let Some(rest) = extra.strip_prefix('~')
+        && (rest.is_empty() || rest.starts_with('/'))
+        && let Some(home) = home
+    {
+        return PathBuf::from(home).join(rest.trim_start_matches('/'));
+    }
+    files_base.join(extra)
+}
 
-    let mut final_file = fs::OpenOptions::new().append(true).open(&output)?;
-    io::copy(&mut File::open(&payload)?, &mut final_file)?;
+// `-f` entries land in the bundle root by basename, so one named like the
+// target, bins or libs would replace what was staged there.
+fn check_reserved_names(files: &[String], files_base: &Path, target_name: &str) -> Result<()> {
+    for extra in files {
+        let name = additional_file_path(files_base, extra);
+        let name = name.file_name().unwrap_or_default();
+        let what = if name == target_name {
+            "target binary"
+        } else if name == "bins" || name == "libs" {
+            "directory"
+        } else {
+            continue;
+        };
+        return Err(RexError::Usage(format!(
+            "-f {extra} would overwrite the staged {what} '{}'; rename it or put it in a subdirectory",
+            name.to_string_lossy()
+        )));
+    }
+    Ok(())
+}
 
-    let metadata = BundleMetadata {
-        payload_size,
-        target_bin_name_len: target_name.len() as u32,
-    };
-    final_file.write_all(target_name.as_bytes())?;
-    let metadata_bytes = unsafe {
-        std::slice::from_raw_parts(
-            &metadata as *const _ as *const u8,
-            size_of::<BundleMetadata>(),
-        )
+// A file that fails to copy (permissions, a file vanishing mid-build) leaves
+// a bundle that breaks at runtime, so it is reported, and is fatal under
+// --strict-deps, rather than ignored.
+fn check_copy<T, E: fmt::Display>(
+    result: std::result::Result<T, E>,
+    path: &Path,
+    strict: bool,
+) -> Result<()> {
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) if strict => Err(RexError::CopyFailed {
+            path: path.to_path_buf(),
+            cause: e.to_string(),
+        }),
+        Err(e) => {
+            warning!("[Warning] Failed to copy {}: {e}", path.display());
+            Ok(())
+        }
+    }
+}
+
+// Symlinks among the -f files are kept as links unless --dereference-data
+// asks for their targets' contents.
+fn stage_additional_files(
+    args: &BundleArgs,
+    staging_dir: &Path,
+    target_name: &str,
+    files_base: &Path,
+    mut hardlinks: Option<&mut HardlinkMap>,
+) -> Result<Vec<PathBuf>> {
+    check_reserved_names(&args.additional_files, files_base, target_name)?;
+    let follow_symlinks = args.dereference_data;
+    let coptions = CopyOptions {
+        follow_symlinks,
+        ..Default::default()
     };
-    final_file.write_all(metadata_bytes)?;
-    final_file.write_all(&MAGIC_MARKER)?;
+    let mut extra_dests = vec![];
+    for extra in &args.additional_files {
+        let path = additional_file_path(files_base, extra);
+        let dest = staging_dir.join(path.file_name().unwrap_or_default());
+        if !follow_symlinks && path.is_symlink() {
+            info!("[Staging] Copying symlink: {}", path.display());
+            fs::remove_file(&dest).ok();
+            symlink(fs::read_link(&path)?, &dest)?;
+        } else if path.is_dir() {
+            if args.no_recreate {
+                fs::create_dir_all(&dest)?;
+            } else {
+                recreate_dir(&dest)?;
+            }
+            info!("[Staging] Copying directory: {}", path.display());
+            let copied = copy_tree(&path, &dest, follow_symlinks, hardlinks.as_deref_mut());
+            check_copy(copied, &path, args.strict_deps)?;
+        } else {
+            info!("[Staging] Copying file: {}", path.display());
+            if !same_contents(&path, &dest) {
+                fs::remove_file(&dest).ok();
+                let copied = copy_recursive(&path, staging_dir, &coptions);
+                check_copy(copied, &path, args.strict_deps)?;
+            }
+        }
+        if args.overrides.mtime.is_none() {
+            copy_mtimes(&path, &dest, follow_symlinks)?;
+        }
+        extra_dests.push(dest);
+    }
+    Ok(extra_dests)
+}
 
-    fs::remove_file(&payload).ok();
-    fs::remove_dir_all(&staging_dir).ok();
+// Copying gives files the staging time; `-f` files keep their own, which
+// the TAR headers then carry to the extracted tree. Symlinks are skipped.
+fn copy_mtimes(src: &Path, dest: &Path, follow_symlinks: bool) -> Result<()> {
+    let Ok(meta) = dest.symlink_metadata() else {
+        return Ok(());
+    };
+    if meta.is_symlink() {
+        return Ok(());
+    }
+    if meta.is_dir() {
+        for entry in fs::read_dir(dest)? {
+            let name = entry?.file_name();
+            copy_mtimes(&src.join(&name), &dest.join(&name), follow_symlinks)?;
+        }
+    }
+    let src_meta = if follow_symlinks {
+        fs::metadata(src)
+    } else {
+        fs::symlink_metadata(src)
+    };
+    if let Ok(modified) = src_meta.and_then(|m| m.modified()) {
+        File::open(dest)?.set_modified(modified)?;
+    }
+    Ok(())
+}
 
-    println!(
-        "\n[Generator Success]\n  Payload Size: {payload_size} bytes\n  Metadata Size: {} bytes",
-        size_of::<BundleMetadata>() + target_name.len() + MAGIC_MARKER.len()
-    );
+fn run_tool(cmd: &mut Command, tool: &str, flag: &str, path: &Path) -> Result<()> {
+    let status = cmd.status().map_err(|cause| RexError::ToolNotRun {
+        tool: tool.to_string(),
+        flag: flag.to_string(),
+        cause,
+    })?;
+    if !status.success() {
+        return Err(RexError::ToolFailed {
+            tool: tool.to_string(),
+            path: path.to_path_buf(),
+        });
+    }
     Ok(())
 }
+
+// Strips the target, bins/ and libs/ (never the loader). With `include_debug`
+// the debug info is first split off into `debug/<path>.debug` and linked
+// back via `.gnu_debuglink`, so crashes can still be symbolized later.
+fn strip_binaries(staging_dir: &Path, target_name: &str, include_debug: bool) -> Result<()> {
+    let mut jobs = vec![staging_dir.join(target_name)];
+    for dir in [staging_dir.join("bins"), staging_dir.join("libs")] {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if !elf::is_loader(&path) && path.symlink_metadata()?.is_file() {
+                jobs.push(path);
+            }
+        }
+    }
+
+    info!("[Staging] Stripping staged binaries...");
+    let mut saved = 0;
+    for path in jobs.into_iter().filter(|p| Elf::open(p).is_ok()) {
+        let before = path.metadata()?.len();
+        let debug = match path.strip_prefix(staging_dir) {
+            Ok(rel) if include_debug => {
+                let debug = staging_dir
+                    .join("debug")
+                    .join(format!("{}.debug", rel.display()));
+                fs::create_dir_all(debug.parent().unwrap_or(staging_dir))?;
+                let mut cmd = Command::new("objcopy");
+                cmd.arg("--only-keep-debug").arg(&path).arg(&debug);
+                run_tool(&mut cmd, "objcopy", "--include-debug", &path)?;
+                Some(debug)
+            }
+            _ => None,
+        };
+
+        let mut cmd = Command::new("strip");
+        cmd.arg("--strip-unneeded").arg(&path);
+        run_tool(&mut cmd, "strip", "--strip", &path)?;
+        if let Some(debug) = debug {
+            let mut cmd = Command::new("objcopy");
+            cmd.arg(format!("--add-gnu-debuglink={}", debug.display()))
+                .arg(&path);
+            run_tool(&mut cmd, "objcopy", "--include-debug", &path)?;
+        }
+        saved += before.saturating_sub(path.metadata()?.len());
+    }
+    info!("[Staging] Stripping saved {saved} bytes");
+    Ok(())
+}
+
+// Points RPATH of every staged ELF at the bundled libs and, for executables,
+// PT_INTERP at the bundled loader as it will be extracted to the default
+// location, so they also resolve correctly when run without the wrapper.
+fn relink_rpaths(staging_dir: &Path, target_name: &str) -> Result<PathBuf> {
+    let libs_dir = staging_dir.join("libs");
+    let loader = Runtime::find_loader(&libs_dir)?;
+    let interp = env::temp_dir()
+        .join(format!("{target_name}_bundle"))
+        .join("libs")
+        .join(loader.file_name().unwrap_or_default());
+
+    let mut jobs = vec![(staging_dir.join(target_name), "$ORIGIN/libs", true)];
+    for (dir, rpath, is_exec) in [
+        (staging_dir.join("bins"), "$ORIGIN/../libs", true),
+        (libs_dir, "$ORIGIN", false),
+    ] {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if !elf::is_loader(&path) && path.symlink_metadata()?.is_file() {
+                jobs.push((path, rpath, is_exec));
+            }
+        }
+    }
+
+    info!("[Staging] Relinking RPATH of staged binaries...");
+    for (path, rpath, is_exec) in jobs {
+        let Ok(elf) = Elf::open(&path) else {
+            continue;
+        };
+        let mut cmd = Command::new("patchelf");
+        cmd.arg("--force-rpath").arg("--set-rpath").arg(rpath);
+        if is_exec && elf.interpreter().is_some() {
+            cmd.arg("--set-interpreter").arg(&interp);
+        }
+        cmd.arg(&path);
+        run_tool(&mut cmd, "patchelf", "--relink-rpath", &path)?;
+    }
+    Ok(interp)
+}
+
+// Compares resolved paths where they exist, so neither symlinks nor `..`
+// in `-o` hide the overlap; the output itself usually does not exist yet.
+fn is_within(path: &Path, dir: &Path) -> bool {
+    let resolve = |path: &Path| {
+        let absolute = env::current_dir().unwrap_or_default().join(path);
+        let mut existing = absolute.as_path();
+        let mut rest = vec![];
+        while let Some(parent) = existing.parent() {
+            if let Ok(real) = fs::canonicalize(existing) {
+                return rest
+                    .iter()
+                    .rev()
+                    .fold(real, |p: PathBuf, name| p.join(name));
+            }
+            rest.extend(existing.file_name());
+            existing = parent;
+        }
+        absolute
+    };
+    resolve(path).starts_with(resolve(dir))
+}
+
+fn validate_target_name(name: &str) -> Result<&str> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+        return Err(RexError::Usage(format!(
+            "Invalid --target-name '{name}': must be a plain file name"
+        )));
+    }
+    Ok(name)
+}
+
+// `-t -` and `-t http(s)://...`: the target is first written to a private
+// temp directory, named after --target-name or the last URL segment, and
+// then goes through the same checks as a local file.
+fn fetch_target(source: &str, target_name: Option<&str>, dest_dir: &Path) -> Result<PathBuf> {
+    let url_name = source
+        .split(['?', '#'])
+        .next()
+        .and_then(|url| url.rsplit('/').next())
+        .filter(|_| source != "-");
+    let name = target_name
+        .or(url_name)
+        .filter(|n| !n.is_empty())
+        .ok_or_else(|| RexError::Usage(format!("Error: -t {source} needs --target-name <name>")))?;
+    let path = dest_dir.join(validate_target_name(name)?);
+    if source == "-" {
+        info!("[Staging] Reading target binary from stdin");
+        io::copy(&mut io::stdin().lock(), &mut File::create(&path)?)?;
+    } else {
+        info!("[Staging] Downloading target binary: {source}");
+        let mut cmd = Command::new("curl");
+        cmd.args(["-fsSL", "-o"]).arg(&path).arg(source);
+        run_tool(&mut cmd, "curl", "-t <url>", Path::new(source))?;
+    }
+    fs::set_permissions(&path, Permissions::from_mode(0o755))?;
+    Ok(path)
+}
+
+// The ELF executables directly inside a `--toolbox` directory, by name.
+fn toolbox_entries(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut tools = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !fs::metadata(&path).is_ok_and(|m| m.is_file() && m.mode() & 0o111 != 0) {
+            continue;
+        }
+        if Elf::open(&path).is_ok() {
+            tools.push(path);
+        } else {
+            warning!(
+                "[Warning] Skipping non-ELF toolbox entry: {}",
+                path.display()
+            );
+        }
+    }
+    tools.sort();
+    if tools.is_empty() {
+        return Err(RexError::Usage(format!(
+            "Error: --toolbox {} contains no ELF executables",
+            dir.display()
+        )));
+    }
+    Ok(tools)
+}
+
+// `--check-only`: the checks a build would fail on, collected into one
+// report instead of stopping at the first, without staging anything.
+fn check_inputs(args: &BundleArgs) -> Result<()> {
+    let mut problems = vec![];
+    if args.include_debug && !args.strip {
+        problems.push("--include-debug requires --strip".to_string());
+    }
+    if args.no_loader_wrapper && !args.relink_rpath {
+        problems.push("--no-loader-wrapper requires --relink-rpath".to_string());
+    }
+    let (min, max) = Codec::Zstd.level_range();
+    if !(min..=max).contains(&args.compression_level) {
+        problems.push(format!(
+            "compression level {} is outside {min}–{max}",
+            args.compression_level
+        ));
+    }
+
+    let mut binaries = vec![];
+    match open_target(&args.target_binary) {
+        Ok(target) => {
+            if let Some(Err(e)) = args.sysroot.as_deref().map(|s| check_sysroot(&target, s)) {
+                problems.push(e.to_string());
+            }
+            binaries.push(target);
+        }
+        Err(e) => problems.push(e.to_string()),
+    }
+    let has_target = !binaries.is_empty();
+    for (flag, paths) in [("-b", &args.extra_bins), ("-l", &args.extra_libs)] {
+        for path in paths {
+            if !path.exists() {
+                problems.push(format!("{flag} {}: not found", path.display()));
+            } else if flag == "-b" {
+                binaries.extend(expand_entries(std::slice::from_ref(path))?);
+            }
+        }
+    }
+    for (i, deps) in resolve_all(&binaries, args.sysroot.as_deref())
+        .into_iter()
+        .enumerate()
+    {
+        let path = &binaries[i];
+        match deps {
+            Ok(Some(deps)) => {
+                for (name, _) in deps.iter().filter(|(_, dep)| !dep.exists()) {
+                    problems.push(format!(
+                        "{name} (needed by {}) is unresolved",
+                        path.display()
+                    ));
+                }
+            }
+            Ok(None) if i == 0 && has_target => {
+                problems.push(RexError::NotSharedElf(path.clone()).to_string())
+            }
+            Ok(None) => {}
+            Err(e) => problems.push(e.to_string()),
+        }
+    }
+
+    let files_base = files_base(args)?;
+    for extra in &args.additional_files {
+        let path = additional_file_path(&files_base, extra);
+        if path.symlink_metadata().is_err() {
+            problems.push(format!("-f {extra}: {} not found", path.display()));
+        }
+    }
+    for (flag, path) in [
+        ("--stdin-file", &args.stdin_file),
+        ("--entrypoint-script", &args.entrypoint_script),
+    ] {
+        if let Some(path) = path.as_ref().filter(|p| !p.is_file()) {
+            problems.push(format!("{flag} {}: not found", path.display()));
+        }
+    }
+
+    let target_name = match &args.target_name {
+        Some(name) => validate_target_name(name).map(str::to_string),
+        None => Ok(args
+            .target_binary
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()),
+    };
+    match target_name {
+        Ok(target_name) => {
+            if let Err(e) = check_reserved_names(&args.additional_files, &files_base, &target_name)
+            {
+                problems.push(e.to_string());
+            }
+            let output = args.output.clone().unwrap_or_else(|| {
+                let extension = args.extension.as_deref().unwrap_or(DEFAULT_EXTENSION);
+                PathBuf::from(format!("{target_name}{extension}"))
+            });
+            let staging_dir = env::temp_dir().join(format!("{target_name}_bundle"));
+            if is_within(&output, &staging_dir) {
+                problems.push(format!(
+                    "output {} is inside the staging directory",
+                    output.display()
+                ));
+            }
+            let parent = match output.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            let probe = parent.join(format!(".rex_check_{}", std::process::id()));
+            match File::create(&probe) {
+                Ok(_) => {
+                    fs::remove_file(&probe).ok();
+                }
+                Err(e) => {
+                    problems.push(format!("output {} is not writable: {e}", output.display()))
+                }
+            }
+        }
+        Err(e) => problems.push(e.to_string()),
+    }
+
+    if problems.is_empty() {
+        info!("[Check] All inputs look fine; the build can start");
+        return Ok(());
+    }
+    for problem in &problems {
+        println!("[Check] {problem}");
+    }
+    Err(RexError::ChecksFailed(problems.len()))
+}
+
+pub fn generate_bundle(mut args: BundleArgs) -> Result<()> {
+    Runtime::ensure_linux()?;
+    let source = args.target_binary.to_string_lossy().into_owned();
+    if source == "-" || source.starts_with("http://") || source.starts_with("https://") {
+        let dest_dir = env::temp_dir().join(format!("rex_target_{}", std::process::id()));
+        recreate_dir(&dest_dir)?;
+        let result = fetch_target(&source, args.target_name.as_deref(), &dest_dir).and_then(
+            |target_binary| {
+                generate_bundle(BundleArgs {
+                    target_binary,
+                    ..args
+                })
+            },
+        );
+        fs::remove_dir_all(&dest_dir).ok();
+        return result;
+    }
+    // A toolbox is staged like -t plus -b: the first tool (or -t, if given)
+    // becomes the target and the others extra bins.
+    let toolbox = match args.toolbox.take() {
+        Some(dir) => {
+            let mut tools = toolbox_entries(&dir)?.into_iter();
+            if args.target_binary.as_os_str().is_empty() {
+                args.target_binary = tools.next().unwrap_or_default();
+            }
+            args.extra_bins.splice(0..0, tools);
+            true
+        }
+        None => false,
+    };
+    if args.check_only {
+        return check_inputs(&args);
+    }
+    if args.include_debug && !args.strip {
+        return Err(RexError::Usage(
+            "Error: --include-debug requires --strip".into(),
+        ));
+    }
+    if args.no_loader_wrapper && !args.relink_rpath {
+        return Err(RexError::Usage(
+            "Error: --no-loader-wrapper requires --relink-rpath".into(),
+        ));
+    }
+
+    // Resolve symlinks so dependencies are looked up next to the real file;
+    // the bundled name stays the one given with -t unless asked otherwise,
+    // since argv[0]-driven programs usually expect the invoked name.
+    let target = &open_target(&args.target_binary)?;
+    if let Some(sysroot) = &args.sysroot {
+        check_sysroot(target, sysroot)?;
+    }
+    let extra_bins = expand_entries(&args.extra_bins)?;
+    let extra_libs = expand_entries(&args.extra_libs)?;
+
+    let resolved_libs: &[PathBuf] = if args.no_extra_libs_deps {
+        &[]
+    } else {
+        &extra_libs
+    };
+    let inputs: Vec<PathBuf> = [target.clone()]
+        .into_iter()
+        .chain(extra_bins.iter().cloned())
+        .chain(resolved_libs.iter().cloned())
+        .collect();
+    let mut resolved = resolve_all(&inputs, args.sysroot.as_deref()).into_iter();
+    let deps = resolved
+        .next()
+        .unwrap_or(Ok(None))?
+        .ok_or_else(|| RexError::NotSharedElf(target.clone()))?;
+    let bin_deps = resolved
+        .by_ref()
+        .take(extra_bins.len())
+        .map(|r| r.map(Option::unwrap_or_default))
+        .collect::<Result<Vec<_>>>()?;
+    // Extra libs may be files rldd cannot parse; those simply add no deps.
+    let lib_deps: Vec<Deps> = resolved
+        .map(|r| r.ok().flatten().unwrap_or_default())
+        .collect();
+
+    let name_source = if args.resolve_target_name {
+        target
+    } else {
+        &args.target_binary
+    };
+    let target_name = match &args.target_name {
+        Some(name) => validate_target_name(name)?,
+        None => name_source
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| {
+                RexError::Usage(format!(
+                    "Invalid target path {}: needs a UTF-8 file name",
+                    name_source.display()
+                ))
+            })?,
+    };
+    let output = match &args.output {
+        Some(output) => output.clone(),
+        None => {
+            let extension = args.extension.as_deref().unwrap_or(DEFAULT_EXTENSION);
+            PathBuf::from(format!("{target_name}{extension}"))
+        }
+    };
+    if args.output_format == OutputFormat::Rex
+        && fs::canonicalize(&output).is_ok_and(|out| &out == target)
+    {
+        return Err(RexError::Usage(format!(
+            "Error: output {} would overwrite the target; use -o or --extension",
+            output.display()
+        )));
+    }
+    let staging_dir = env::temp_dir().join(format!("{target_name}_bundle"));
+    let payload_dir = env::temp_dir().join(format!("{target_name}_bundle_tmp"));
+    if let Some(dir) = [&staging_dir, &payload_dir]
+        .into_iter()
+        .find(|dir| is_within(&output, dir))
+    {
+        return Err(RexError::Usage(format!(
+            "Error: output {} is inside {}, which is wiped during the build",
+            output.display(),
+            dir.display()
+        )));
+    }
+
+    if args.no_recreate {
+        fs::create_dir_all(&staging_dir)?;
+    } else {
+        recreate_dir(&staging_dir)?;
+    }
+    let bin_dir = staging_dir.join("bins");
+    let libs_dir = staging_dir.join("libs");
+    fs::create_dir_all(&bin_dir)?;
+    fs::create_dir_all(&libs_dir)?;
+
+    let files_base = files_base(&args)?;
+    let mut coptions = CopyOptions::default();
+
+    // Keep the first copy of every soname, so shared libraries are staged
+    // exactly once. Explicit -l entries always win over a resolved library of
+    // the same name; a later binary resolving a different file under that
+    // name (say an extra bin built against another libssl) is reported.
+    let mut staged: HashMap<OsString, (&PathBuf, PathBuf)> = extra_libs
+        .iter()
+        .filter_map(|p| Some((p.file_name()?.to_os_string(), (p, p.clone()))))
+        .collect();
+    let mut libs = vec![];
+    let mut missing = BTreeSet::new();
+    let mut conflicts = vec![];
+    let owners = [target].into_iter().chain(&extra_bins).chain(&extra_libs);
+    let all_deps = [deps].into_iter().chain(bin_deps).chain(lib_deps);
+    for (owner, deps) in owners.zip(all_deps) {
+        for (name, dep) in deps {
+            if !dep.exists() {
+                missing.insert((name, owner));
+                continue;
+            }
+            let file_name = dep.file_name().unwrap_or_default().to_os_string();
+            match staged.get(&file_name) {
+                None => {
+                    staged.insert(file_name, (owner, dep.clone()));
+                    libs.push(dep);
+                }
+                Some((first_owner, first)) if !same_contents(first, &dep) => {
+                    conflicts.push(format!(
+                        "{} needs {}, but {} is already staged for {}",
+                        owner.display(),
+                        dep.display(),
+                        first.display(),
+                        first_owner.display()
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+    }
+    for (lib, owner) in &missing {
+        warning!(
+            "[Warning] Unresolved dependency: {lib} (needed by {})",
+            owner.display()
+        );
+    }
+    if args.strict_deps
+        && let Some((lib, owner)) = missing.pop_first()
+    {
+        return Err(RexError::MissingDependency {
+            lib,
+            needed_by: owner.clone(),
+        });
+    }
+    if let Some(max) = args.max_deps
+        && libs.len() > max
+    {
+        let found: Vec<String> = libs
+            .iter()
+            .map(|lib| {
+                let owner = lib
+                    .file_name()
+                    .and_then(|name| staged.get(name))
+                    .map_or(target, |(owner, _)| *owner);
+                format!("  {} (needed by {})", lib.display(), owner.display())
+            })
+            .collect();
+        return Err(RexError::TooManyDependencies { max, libs: found });
+    }
+    if args.strict_deps && !conflicts.is_empty() {
+        return Err(RexError::LibraryConflict(conflicts));
+    }
+    for conflict in &conflicts {
+        warning!("[Warning] {conflict}; keeping the first");
+    }
+
+    info!("[Staging] Copying target binary: {}", target.display());
+    copy_file(target, &staging_dir.join(target_name))?;
+
+    if !extra_bins.is_empty() {
+        info!(
+            "[Staging] Processing {} extra binaries...",
+            extra_bins.len()
+        );
+        for bin in &extra_bins {
+            let dest = bin_dir.join(bin.file_name().unwrap_or_default());
+            copy_file(bin, &dest)?;
+            info!("[Staging] Copied binary: {}", dest.display());
+        }
+    }
+
+    info!("[Staging] Copying {} unique shared libs...", libs.len());
+    coptions.content_only = true;
+    coptions.follow_symlinks = true;
+    for lib in &libs {
+        debug!("stage {} -> {}", lib.display(), libs_dir.display());
+        check_copy(
+            copy_recursive(lib, &libs_dir, &coptions),
+            lib,
+            args.strict_deps,
+        )?;
+    }
+
+    let mut hardlinks = args.preserve_hardlinks.then(HardlinkMap::new);
+    if !extra_libs.is_empty() {
+        info!("[Staging] Copying {} extra libs...", extra_libs.len());
+        coptions.follow_symlinks = false;
+        for lib in &extra_libs {
+            if let Some(links) = hardlinks.as_mut() {
+                let dest = libs_dir.join(lib.file_name().unwrap_or_default());
+                let copied = copy_tree(lib, &dest, false, Some(links));
+                check_copy(copied, lib, args.strict_deps)?;
+            } else {
+                let copied = copy_recursive(lib, &libs_dir, &coptions);
+                check_copy(copied, lib, args.strict_deps)?;
+            }
+        }
+    }
+
+    let extra_dests = stage_additional_files(
+        &args,
+        &staging_dir,
+        target_name,
+        &files_base,
+        hardlinks.as_mut(),
+    )?;
+    if args.follow_interpreter {
+        libs.extend(follow_interpreters(
+            &extra_dests,
+            &bin_dir,
+            &libs_dir,
+            args.strict_deps,
+        )?);
+    }
+    if args.prune_unused_libs {
+        prune_unused_libs(&staging_dir, target_name)?;
+    }
+    check_loader_abi(&libs_dir, args.strict_deps)?;
+    let staged_from: HashMap<OsString, PathBuf> = libs
+        .iter()
+        .chain(&extra_libs)
+        .filter_map(|p| Some((p.file_name()?.to_os_string(), p.clone())))
+        .collect();
+    check_soname_conflicts(&libs_dir, &staged_from, args.strict_deps)?;
+    if args.strip {
+        strip_binaries(&staging_dir, target_name, args.include_debug)?;
+    }
+
+    if args.output_format == OutputFormat::Rootfs {
+        let lib_sources: HashMap<OsString, PathBuf> = libs
+            .iter()
+            .chain(&extra_libs)
+            .filter_map(|p| Some((p.file_name()?.to_os_string(), p.parent()?.to_path_buf())))
+            .collect();
+        let interp = Elf::open(target)?.interpreter();
+        let rootfs = PathBuf::from(format!("{target_name}.rootfs"));
+
+        info!("[Output] Creating rootfs: {}", rootfs.display());
+        build_rootfs(&staging_dir, &rootfs, target_name, &lib_sources, interp)?;
+        if args.overrides.owner.is_some() || args.overrides.mode.is_some() {
+            apply_overrides(&rootfs, args.overrides)?;
+        }
+        if !args.no_recreate {
+            fs::remove_dir_all(&staging_dir).ok();
+        }
+        println!("\n[Generator Success]\n  Rootfs: {}", rootfs.display());
+        return Ok(());
+    }
+
+    let mut options = BundleOptions {
+        timeout: args.timeout,
+        cache: args.cache,
+        path_append: args.no_path_prepend,
+        secure_cleanup: args.secure_cleanup,
+        clean_env: args.clean_env,
+        clean_env_prefixes: args.clean_env_prefixes.clone(),
+        keep_env: args.keep_env.clone(),
+        arch: Elf::open(target)?.arch().map(str::to_string),
+        post_extract: args.post_extract.clone(),
+        install_dir: args.install_dir.clone(),
+        install_command: args.install_command.clone(),
+        pidfile: args.pidfile.clone(),
+        prepend_args: args.prepend_args.clone(),
+        loader_args: args.loader_args.clone(),
+        tools: if toolbox {
+            [target_name.to_string()]
+                .into_iter()
+                .chain(extra_bins.iter().map(|bin| {
+                    bin.file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .into_owned()
+                }))
+                .collect()
+        } else {
+            vec![]
+        },
+        min_glibc: args.min_glibc.clone(),
+        debug_info: args.strip && args.include_debug,
+        capabilities: capture_capabilities(target, target_name, &extra_bins)?,
+        ..Default::default()
+    };
+    if args.secure_cleanup && args.cache {
+        warning!("[Warning] --secure-cleanup has no effect with --concurrency-safe-cache");
+    }
+    if let Some(stdin_file) = &args.stdin_file {
+        info!("[Staging] Copying stdin file: {}", stdin_file.display());
+        fs::copy(stdin_file, staging_dir.join(STDIN_FILE_NAME))?;
+        options.stdin_file = Some(STDIN_FILE_NAME.to_string());
+    }
+    if let Some(script) = &args.entrypoint_script {
+        info!("[Staging] Copying entrypoint script: {}", script.display());
+        let dest = staging_dir.join(ENTRYPOINT_SCRIPT_NAME);
+        fs::copy(script, &dest)?;
+        fs::set_permissions(&dest, Permissions::from_mode(0o755))?;
+        options.entrypoint_script = Some(ENTRYPOINT_SCRIPT_NAME.to_string());
+    }
+
+    if args.relink_rpath {
+        let interp = relink_rpaths(&staging_dir, target_name)?;
+        options.interpreter = Some(interp.to_string_lossy().into_owned());
+        options.relinked = true;
+        options.no_loader_wrapper = args.no_loader_wrapper;
+    }
+
+    write_bundle(
+        &args,
+        &staging_dir,
+        target_name,
+        &extra_dests,
+        options,
+        &output,
+    )
+}
+
+// Unpacks `bundle`, lets `edit` change the staged tree (returning the `-f`
+// destinations it added) and atomically replaces the bundle with the
+// rebuilt one, keeping the baked options unless `edit` updates them.
+fn edit_bundle(
+    bundle: &Path,
+    args: &BundleArgs,
+    edit: impl FnOnce(&Path, &str, &mut BundleOptions) -> Result<Vec<PathBuf>>,
+) -> Result<()> {
+    let work_dir = env::temp_dir().join(format!("rex_edit_{}", std::process::id()));
+    recreate_dir(&work_dir)?;
+
+    info!("[Staging] Unpacking bundle: {}", bundle.display());
+    let result =
+        Runtime::unpack_bundle(bundle, &work_dir).and_then(|(target_name, mut options)| {
+            // Stored files were unpacked into the tree; they are only split out
+            // again when --store/--store-compressed is given for this run.
+            options.store_size = None;
+            options.dedup_store = None;
+            options.blobs.clear();
+            let staging_dir = work_dir.join(format!("{target_name}_bundle"));
+            let extra_dests = edit(&staging_dir, &target_name, &mut options)?;
+
+            let output = PathBuf::from(format!("{}.tmp", bundle.display()));
+            write_bundle(
+                args,
+                &staging_dir,
+                &target_name,
+                &extra_dests,
+                options,
+                &output,
+            )?;
+            fs::rename(&output, bundle)?;
+            Ok(())
+        });
+
+    fs::remove_dir_all(&work_dir).ok();
+    result
+}
+
+fn files_base(args: &BundleArgs) -> Result<PathBuf> {
+    match &args.files_base {
+        Some(base) => Ok(base.clone()),
+        None => Ok(env::current_dir()?),
+    }
+}
+
+/// Adds or replaces `-f` entries in an existing bundle. The target, libs and
+/// baked options are carried over unchanged; only the payload is rebuilt.
+pub fn append_files(bundle: &Path, args: BundleArgs) -> Result<()> {
+    if args.additional_files.is_empty() {
+        return Err(RexError::Usage(
+            "Error: --append-to needs at least one -f <path>".into(),
+        ));
+    }
+    edit_bundle(bundle, &args, |staging_dir, target_name, _| {
+        stage_additional_files(&args, staging_dir, target_name, &files_base(&args)?, None)
+    })
+}
+
+/// Swaps the target of an existing bundle for `new_target`, staging any
+/// library it needs that the bundle lacks. Libraries only the old target
+/// needed are kept unless `--prune-unused-libs` is given.
+pub fn replace_target(bundle: &Path, new_target: &Path, args: BundleArgs) -> Result<()> {
+    let target = open_target(new_target)?;
+    let deps = resolve_deps(&target)?.ok_or_else(|| RexError::NotSharedElf(target.clone()))?;
+
+    edit_bundle(bundle, &args, |staging_dir, target_name, options| {
+        info!("[Staging] Replacing target with: {}", target.display());
+        let dest = staging_dir.join(target_name);
+        fs::remove_file(&dest).ok();
+        fs::copy(&target, &dest)?;
+        retarget_options(options, &target, target_name)?;
+
+        let libs_dir = staging_dir.join("libs");
+        let coptions = CopyOptions {
+            content_only: true,
+            follow_symlinks: true,
+            ..Default::default()
+        };
+        let mut added = 0;
+        for (_, dep) in deps.iter().filter(|(_, p)| p.exists()) {
+            if !libs_dir.join(dep.file_name().unwrap_or_default()).exists() {
+                info!("[Staging] Adding new lib: {}", dep.display());
+                check_copy(
+                    copy_recursive(dep, &libs_dir, &coptions),
+                    dep,
+                    args.strict_deps,
+                )?;
+                added += 1;
+            }
+        }
+        info!("[Staging] Added {added} new libs");
+
+        if args.prune_unused_libs {
+            prune_unused_libs(staging_dir, target_name)?;
+        }
+        stage_additional_files(&args, staging_dir, target_name, &files_base(&args)?, None)
+    })
+}
+
+// The options describing the target itself belong to the old binary: its
+// architecture decides about qemu, and its capabilities must not be granted
+// to whatever replaces it.
+fn retarget_options(options: &mut BundleOptions, target: &Path, target_name: &str) -> Result<()> {
+    options.arch = Elf::open(target)?.arch().map(str::to_string);
+    let mut capabilities = capture_capabilities(target, target_name, &[])?;
+    capabilities.extend(
+        options
+            .capabilities
+            .drain(..)
+            .filter(|(path, _)| path != target_name),
+    );
+    options.capabilities = capabilities;
+    Ok(())
+}
+
+// File capabilities (e.g. cap_net_raw on ping) live in an xattr that neither
+// the copy nor the TAR keeps, so they are recorded in the options and set
+// again after extraction.
+fn capture_capabilities(
+    target: &Path,
+    target_name: &str,
+    extra_bins: &[PathBuf],
+) -> Result<Vec<(String, Vec<u8>)>> {
+    let sources = [(target.to_path_buf(), target_name.to_string())]
+        .into_iter()
+        .chain(extra_bins.iter().map(|bin| {
+            let name = bin.file_name().unwrap_or_default().to_string_lossy();
+            (bin.clone(), format!("bins/{name}"))
+        }));
+    let mut capabilities = vec![];
+    for (src, dest) in sources {
+        if let Some(value) = fsutil::get_xattr(&src, CAPABILITY_XATTR)? {
+            warning!(
+                "[Warning] {} has file capabilities; they are only restored with CAP_SETFCAP",
+                src.display()
+            );
+            capabilities.push((dest, value));
+        }
+    }
+    Ok(capabilities)
+}
+
+// A loader and libc.so.6 from different glibc releases typically segfault
+// before main(); warn about it, or fail with --strict-deps.
+fn check_loader_abi(libs_dir: &Path, strict: bool) -> Result<()> {
+    let Some(loader) = fs::read_dir(libs_dir)?
+        .flatten()
+        .map(|e| e.path())
+        .find(|p| elf::is_loader(p))
+    else {
+        return Ok(());
+    };
+    let libc = libs_dir.join("libc.so.6");
+    if !libc.exists() {
+        return Ok(());
+    }
+    let (Some(loader_version), Some(libc_version)) =
+        (elf::glibc_version(&loader)?, elf::glibc_version(&libc)?)
+    else {
+        return Ok(());
+    };
+    if loader_version == libc_version {
+        return Ok(());
+    }
+
+    let mismatch = RexError::LoaderAbiMismatch {
+        loader: loader
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+        loader_version,
+        libc_version,
+    };
+    if strict {
+        return Err(mismatch);
+    }
+    warning!("[Warning] {mismatch}");
+    Ok(())
+}
+
+// Two staged files with the same DT_SONAME (say libfoo.so.1.2 and
+// libfoo.so.1.5, resolved for different binaries) leave it to the search
+// order which one the loader picks.
+fn check_soname_conflicts(
+    libs_dir: &Path,
+    sources: &HashMap<OsString, PathBuf>,
+    strict: bool,
+) -> Result<()> {
+    let mut providers: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for entry in fs::read_dir(libs_dir)?.flatten() {
+        let path = entry.path();
+        if !path.symlink_metadata().is_ok_and(|m| m.is_file()) || elf::is_loader(&path) {
+            continue;
+        }
+        let Some(soname) = Elf::open(&path).ok().and_then(|elf| elf.soname()) else {
+            continue;
+        };
+        let name = entry.file_name();
+        let provider = match sources.get(&name) {
+            Some(source) => format!("{} (from {})", name.to_string_lossy(), source.display()),
+            None => name.to_string_lossy().into_owned(),
+        };
+        providers.entry(soname).or_default().push(provider);
+    }
+
+    let conflicts: Vec<String> = providers
+        .into_iter()
+        .filter(|(_, files)| files.len() > 1)
+        .map(|(soname, mut files)| {
+            files.sort();
+            format!("{soname} is provided by {}", files.join(", "))
+        })
+        .collect();
+    if strict && !conflicts.is_empty() {
+        return Err(RexError::LibraryConflict(conflicts));
+    }
+    for conflict in conflicts {
+        warning!("[Warning] {conflict}; the loader may pick either");
+    }
+    Ok(())
+}
+
+// Removes every staged lib outside the DT_NEEDED closure of the target and
+// bins/. The loader is always kept; libraries only loaded with dlopen() are
+// not seen and get removed as well.
+fn prune_unused_libs(staging_dir: &Path, target_name: &str) -> Result<()> {
+    let libs_dir = staging_dir.join("libs");
+    let mut queue = vec![staging_dir.join(target_name)];
+    for entry in fs::read_dir(staging_dir.join("bins"))? {
+        queue.push(entry?.path());
+    }
+    if let Ok(interpreters) = fs::read_dir(staging_dir.join("bins").join(INTERPRETER_DIR)) {
+        for entry in interpreters {
+            queue.push(entry?.path());
+        }
+    }
+
+    let mut keep = HashSet::new();
+    while let Some(path) = queue.pop() {
+        let Ok(elf) = Elf::open(&path) else {
+            continue;
+        };
+        for name in elf.needed() {
+            let lib = libs_dir.join(&name);
+            if keep.insert(OsString::from(&name)) && lib.exists() {
+                if let Some(real) = fs::canonicalize(&lib)
+                    .ok()
+                    .and_then(|p| p.file_name().map(OsString::from))
+                {
+                    keep.insert(real);
+                }
+                queue.push(lib);
+            }
+        }
+    }
+
+    let (mut pruned, mut saved) = (0, 0);
+    for entry in fs::read_dir(&libs_dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let meta = entry.metadata()?;
+        if meta.is_dir() || keep.contains(&entry.file_name()) || elf::is_loader(&path) {
+            continue;
+        }
+        info!(
+            "[Staging] Pruning unused lib: {}",
+            entry.file_name().to_string_lossy()
+        );
+        fs::remove_file(&path)?;
+        pruned += 1;
+        saved += meta.len();
+    }
+    info!("[Staging] Pruned {pruned} libs, saving {saved} bytes");
+    Ok(())
+}
+
+// Compresses the staged tree once per benchmark level and reports size and
+// time, before the bundle itself is written at the requested level.
+fn benchmark_levels(staging_dir: &Path, target_name: &str, args: &BundleArgs) -> Result<()> {
+    let mut results = vec![];
+    let mut uncompressed = 0;
+    for &level in BENCHMARK_LEVELS {
+        let started = Instant::now();
+        let (payload, size) = create_payload(
+            staging_dir,
+            target_name,
+            level,
+            args.overrides,
+            args.tar_format,
+        )?;
+        let elapsed = started.elapsed().as_millis();
+        results.push((level, payload.metadata()?.len(), elapsed));
+        fs::remove_file(&payload).ok();
+        uncompressed = size;
+    }
+
+    if args.json {
+        let rows: Vec<String> = results
+            .iter()
+            .map(|(level, size, ms)| {
+                format!("{{\"level\":{level},\"compressed\":{size},\"duration_ms\":{ms}}}")
+            })
+            .collect();
+        println!(
+            "{{\"uncompressed\":{uncompressed},\"benchmark\":[{}]}}",
+            rows.join(",")
+        );
+    } else {
+        println!("[Benchmark] Uncompressed TAR: {uncompressed} bytes");
+        println!(
+            "[Benchmark] {:>5} {:>14} {:>7} {:>10}",
+            "level", "bytes", "ratio", "ms"
+        );
+        for (level, size, ms) in &results {
+            let ratio = uncompressed as f64 / (*size).max(1) as f64;
+            println!("[Benchmark] {level:>5} {size:>14} {ratio:>7.3} {ms:>10}");
+        }
+    }
+    Ok(())
+}
+
+// Reads the first `fraction` of every regular file under `path` into `out`.
+fn sample_tree(path: &Path, fraction: f64, out: &mut Vec<u8>) -> io::Result<()> {
+    let meta = fs::symlink_metadata(path)?;
+    if meta.is_dir() {
+        for entry in fs::read_dir(path)? {
+            sample_tree(&entry?.path(), fraction, out)?;
+        }
+    } else if meta.is_file() {
+        let take = (meta.len() as f64 * fraction).ceil() as u64;
+        File::open(path)?.take(take).read_to_end(out)?;
+    }
+    Ok(())
+}
+
+// `--time-budget`: compresses a proportional slice of every staged file at
+// two levels, scales the timings to the whole tree and interpolates them
+// log-linearly to pick the highest level expected to fit the budget.
+// Returns the level and its estimated compression time.
+fn tune_level(staging_dir: &Path, budget: Duration) -> Result<(i32, Duration)> {
+    let total = fsutil::tree_size(staging_dir)?;
+    let fraction = (TUNE_SAMPLE_SIZE as f64 / total.max(1) as f64).min(1.0);
+    let mut sample = vec![];
+    sample_tree(staging_dir, fraction, &mut sample)?;
+    let scale = total as f64 / sample.len().max(1) as f64;
+
+    let time_at = |level: i32| -> Result<f64> {
+        let started = Instant::now();
+        let mut encoder = Encoder::new(io::sink(), level)?;
+        encoder.long_distance_matching(true)?;
+        encoder.write_all(&sample)?;
+        encoder.finish()?;
+        Ok(started.elapsed().as_secs_f64() * scale)
+    };
+    let (low, high) = TUNE_LEVELS;
+    let (low_secs, high_secs) = (time_at(low)?.max(1e-6), time_at(high)?.max(1e-6));
+    debug!(
+        "tune: {} of {total} bytes sampled, level {low} ~{low_secs:.2}s, level {high} ~{high_secs:.2}s",
+        sample.len()
+    );
+    let estimate = |level: i32| {
+        low_secs * (high_secs / low_secs).powf((level - low) as f64 / (high - low) as f64)
+    };
+
+    let level = (1..=high)
+        .rev()
+        .find(|level| estimate(*level) <= budget.as_secs_f64())
+        .unwrap_or(1);
+    Ok((level, Duration::from_secs_f64(estimate(level))))
+}
+
+// Aborts the build when `size` exceeds `--size-limit`, listing the staged
+// libs largest first so it is clear what to trim.
+fn check_size_limit(args: &BundleArgs, staging_dir: &Path, size: u64) -> Result<()> {
+    let Some(limit) = args.size_limit.filter(|limit| size > *limit) else {
+        return Ok(());
+    };
+
+    let mut libs = vec![];
+    if let Ok(entries) = fs::read_dir(staging_dir.join("libs")) {
+        for entry in entries.flatten() {
+            if let Ok(meta) = entry.path().symlink_metadata()
+                && meta.is_file()
+            {
+                libs.push((meta.len(), entry.file_name().to_string_lossy().into_owned()));
+            }
+        }
+    }
+    libs.sort_by(|a, b| b.cmp(a));
+    warning!("[Warning] Staged libs by size (uncompressed):");
+    for (lib_size, name) in &libs {
+        warning!("  {lib_size:>12}  {name}");
+    }
+
+    Err(RexError::SizeLimit {
+        what: args.size_limit_on.describe(),
+        size,
+        limit,
+    })
+}
+
+fn stamp_launcher(output: &Path) -> Result<()> {
+    let needle = &LAUNCHER_STAMP[..LAUNCHER_STAMP.len() - 1];
+    let data = fs::read(output)?;
+    let mut file = fs::OpenOptions::new().write(true).open(output)?;
+    for (pos, _) in data
+        .windows(LAUNCHER_STAMP.len())
+        .enumerate()
+        .filter(|(_, w)| w.starts_with(needle))
+    {
+        file.seek(SeekFrom::Start((pos + needle.len()) as u64))?;
+        file.write_all(&[1])?;
+    }
+    Ok(())
+}
+
+// `--dedup-across-bundles <dir>`: moves every staged library but the loader
+// into `<dir>`, named by its SHA-256 so bundles built against the same store
+// share one copy. Returns the store's absolute path and, for each library,
+// its place in the tree and its hash.
+fn move_to_dedup_store(
+    staging_dir: &Path,
+    store: &Path,
+) -> Result<(PathBuf, Vec<(String, String)>)> {
+    fs::create_dir_all(store)?;
+    let store = fs::canonicalize(store)?;
+    let mut blobs = vec![];
+    let mut shared = 0;
+    for entry in fs::read_dir(staging_dir.join("libs"))? {
+        let path = entry?.path();
+        if elf::is_loader(&path) || !path.symlink_metadata()?.is_file() {
+            continue;
+        }
+        let mut digest = Digest::new(Integrity::Sha256);
+        digest.update_reader(File::open(&path)?)?;
+        let hash = digest.finish_hex();
+        let blob = store.join(&hash);
+        if blob.exists() {
+            shared += 1;
+        } else {
+            // Copied under a temp name first, so a concurrent build never
+            // sees a partial blob.
+            let tmp = store.join(format!("{hash}.tmp.{}", std::process::id()));
+            fs::copy(&path, &tmp)?;
+            fs::rename(&tmp, &blob)?;
+        }
+        fs::remove_file(&path)?;
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        blobs.push((format!("libs/{name}"), hash));
+    }
+    info!(
+        "[Staging] Moved {} libs to dedup store {} ({shared} already there)",
+        blobs.len(),
+        store.display()
+    );
+    Ok((store, blobs))
+}
+
+fn write_bundle(
+    args: &BundleArgs,
+    staging_dir: &Path,
+    target_name: &str,
+    extra_dests: &[PathBuf],
+    mut options: BundleOptions,
+    output: &Path,
+) -> Result<()> {
+    let store_dir = env::temp_dir().join(format!("{target_name}_store"));
+    let mut stored = 0;
+    if !args.store_patterns.is_empty() || args.store_compressed {
+        recreate_dir(&store_dir)?;
+        for dest in extra_dests.iter().filter(|d| d.symlink_metadata().is_ok()) {
+            stored += move_stored_files(dest, staging_dir, &store_dir, args)?;
+        }
+        info!("[Staging] Storing {stored} files without compression");
+    }
+
+    if let Some(store) = &args.dedup_store {
+        let (store, blobs) = move_to_dedup_store(staging_dir, store)?;
+        options.dedup_store = Some(store.to_string_lossy().into_owned());
+        options.blobs = blobs;
+    }
+
+    if args.size_limit_on == SizeLimitOn::Uncompressed {
+        let size = fsutil::tree_size(staging_dir)? + fsutil::tree_size(&store_dir).unwrap_or(0);
+        check_size_limit(args, staging_dir, size)?;
+    }
+
+    if args.benchmark {
+        benchmark_levels(staging_dir, target_name, args)?;
+    }
+
+    let mut level = args.compression_level;
+    if !args.level_explicit
+        && let Some(stored) = options.level
+    {
+        info!("[Packaging] Keeping the bundle's compression level {stored}");
+        level = stored;
+    }
+    level = Codec::Zstd.clamp_level(level);
+    let mut estimated = None;
+    if let Some(budget) = args.time_budget {
+        let (tuned, estimate) = tune_level(staging_dir, Duration::from_secs(budget))?;
+        info!(
+            "[Packaging] Time budget {budget}s: chose level {tuned} (estimated {:.1}s)",
+            estimate.as_secs_f64()
+        );
+        level = tuned;
+        estimated = Some(estimate);
+    } else if level >= SLOW_LEVEL && io::stdout().is_terminal() {
+        let size = fsutil::tree_size(staging_dir)?;
+        if size > SLOW_INPUT_SIZE {
+            info!(
+                "[Packaging] Level {level} on {} MB may take several minutes; consider a lower -L",
+                size >> 20
+            );
+        }
+    }
+
+    let started = Instant::now();
+    let (payload, uncompressed_size) = create_payload(
+        staging_dir,
+        target_name,
+        level,
+        args.overrides,
+        args.tar_format,
+    )?;
+    let duration = started.elapsed();
+    if let Some(estimate) = estimated {
+        info!(
+            "[Packaging] Compression took {:.1}s (estimated {:.1}s)",
+            duration.as_secs_f64(),
+            estimate.as_secs_f64()
+        );
+    }
+    let payload_size = payload.metadata()?.len();
+
+    if let Some(stats_out) = &args.stats_out {
+        let lib_count = fs::read_dir(staging_dir.join("libs"))?.count();
+        let stats = format!(
+            "{{\"uncompressed\":{uncompressed_size},\"compressed\":{payload_size},\"ratio\":{:.3},\
+             \"lib_count\":{lib_count},\"level\":{},\"duration_ms\":{}}}\n",
+            uncompressed_size as f64 / payload_size.max(1) as f64,
+            level,
+            duration.as_millis()
+        );
+        fs::write(stats_out, stats)?;
+    }
+
+    if let Some(min) = args.min_compression_savings {
+        let savings = 100.0 - payload_size as f64 * 100.0 / uncompressed_size.max(1) as f64;
+        if savings < min {
+            warning!(
+                "[Warning] Compression saved only {savings:.1}% ({uncompressed_size} -> {payload_size} bytes), \
+                 below {min}%; consider --store for incompressible files"
+            );
+        }
+    }
+    let store = if stored > 0 {
+        Some(create_store(
+            &store_dir,
+            target_name,
+            args.overrides,
+            args.tar_format,
+        )?)
+    } else {
+        None
+    };
+    if args.size_limit_on == SizeLimitOn::Compressed {
+        let store_size = match &store {
+            Some(store) => store.metadata()?.len(),
+            None => 0,
+        };
+        if let Err(e) = check_size_limit(args, staging_dir, payload_size + store_size) {
+            fs::remove_file(&payload).ok();
+            return Err(e);
+        }
+    }
+    let integrity = args.integrity.or(options.integrity).unwrap_or_default();
+    let mut hasher = Fnv64::new();
+    let mut digest = Digest::new(integrity);
+    for part in [Some(&payload), store.as_ref()].into_iter().flatten() {
+        hasher.update_reader(File::open(part)?)?;
+        digest.update_reader(File::open(part)?)?;
+    }
+    if let Some(store) = &store {
+        options.store_size = Some(store.metadata()?.len());
+    }
+    options.unpacked_size = Some(uncompressed_size);
+    options.payload_hash = Some(hasher.finish());
+    options.integrity = Some(integrity);
+    options.codec = Some(Codec::Zstd);
+    options.level = Some(level);
+    let embed_mode = args.embed_mode.or(options.embed_mode).unwrap_or_default();
+    options.embed_mode = Some(embed_mode);
+    options.digest = Some(digest.finish_hex()).filter(|d| !d.is_empty());
+    info!("[Output] Creating bundle: {}", output.display());
+    let launcher_size = fsutil::reflink_or_copy(&Runtime::self_exe()?, output)?;
+    fs::set_permissions(output, Permissions::from_mode(0o755))?;
+    stamp_launcher(output)?;
+
+    let mut final_file = fs::OpenOptions::new().append(true).open(output)?;
+    io::copy(&mut File::open(&payload)?, &mut final_file)?;
+    if let Some(store) = &store {
+        io::copy(&mut File::open(store)?, &mut final_file)?;
+    }
+
+    let options_bytes = options.pack()?;
+    let metadata = BundleMetadata {
+        payload_size,
+        target_bin_name_len: target_name.len() as u32,
+        options_len: options_bytes.len() as u32,
+    };
+    final_file.write_all(target_name.as_bytes())?;
+    final_file.write_all(&options_bytes)?;
+    final_file.write_all(&metadata.to_bytes())?;
+    final_file.write_all(&[FORMAT_VERSION])?;
+    final_file.write_all(&MAGIC_MARKER)?;
+    drop(final_file);
+
+    if embed_mode == EmbedMode::Section {
+        let section_size = fs::metadata(output)?.len() - launcher_size;
+        elf::add_section(output, elf::PAYLOAD_SECTION, launcher_size, section_size)?;
+    }
+
+    if let Some(keep) = &args.keep_payload {
+        info!("[Output] Keeping compressed payload: {}", keep.display());
+        fs::copy(&payload, keep)?;
+    }
+    fs::remove_file(&payload).ok();
+    if !args.no_recreate {
+        fs::remove_dir_all(staging_dir).ok();
+    }
+    if let Some(store) = &store {
+        fs::remove_file(store).ok();
+    }
+    fs::remove_dir_all(&store_dir).ok();
+
+    if args.verify_after_build {
+        info!("[Verify] Checking bundle: {}", output.display());
+        Runtime::verify_bundle(output)
+            .map_err(|e| RexError::BundleCorrupt(format!("verification failed: {e}")))?;
+    }
+
+    let store_size = options.store_size.unwrap_or_default();
+    let metadata_size = size_of::<BundleMetadata>()
+        + target_name.len()
+        + options_bytes.len()
+        + 1
+        + MAGIC_MARKER.len();
+    let total_size = fs::metadata(output)?.len();
+    println!(
+        "\n[Generator Success]\n  Launcher Size: {launcher_size} bytes\n  Payload Size: {payload_size} bytes"
+    );
+    if store_size > 0 {
+        println!("  Stored Size: {store_size} bytes");
+    }
+    println!(
+        "  Metadata Size: {metadata_size} bytes\n  Total Size: {total_size} bytes ({:.1}% overhead)",
+        (total_size - payload_size - store_size) as f64 * 100.0 / total_size.max(1) as f64
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("rex-gen-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn open_target_names_a_missing_file() {
+        let dir = TempDir::new("missing-target");
+        let path = dir.0.join("nope");
+        let err = open_target(&path).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            format!(
+                "Failed to analyze dependencies of {}: file not found; check the -t path",
+                path.display()
+            )
+        );
+    }
+
+    #[test]
+    fn open_target_rejects_non_elf_files() {
+        let dir = TempDir::new("script-target");
+        let path = dir.0.join("run.sh");
+        fs::write(&path, "#!/bin/sh\nexec true\n").unwrap();
+        let err = open_target(&path).unwrap_err().to_string();
+        let real = fs::canonicalize(&path).unwrap();
+        assert_eq!(
+            err,
+            format!("not an ELF binary: {} (no ELF magic)", real.display())
+        );
+
+        let err = open_target(&dir.0).unwrap_err().to_string();
+        assert!(err.ends_with("is a directory, not an executable"), "{err}");
+    }
+
+    #[test]
+    fn reserved_names_are_rejected() {
+        let base = Path::new("/srv/app");
+        let check = |files: &[&str]| {
+            let files: Vec<String> = files.iter().map(|f| f.to_string()).collect();
+            check_reserved_names(&files, base, "app").map_err(|e| e.to_string())
+        };
+        assert_eq!(
+            check(&["bins"]).unwrap_err(),
+            "-f bins would overwrite the staged directory 'bins'; \
+             rename it or put it in a subdirectory"
+        );
+        assert!(
+            check(&["data/libs"])
+                .unwrap_err()
+                .contains("directory 'libs'")
+        );
+        assert!(
+            check(&["/opt/app"])
+                .unwrap_err()
+                .contains("target binary 'app'")
+        );
+        assert_eq!(check(&["config", "bins/extra", "app.conf"]), Ok(()));
+    }
+
+    #[test]
+    fn additional_file_paths_expand_home_only() {
+        let base = Path::new("/srv/build");
+        let home = || Some(OsString::from("/home/dev"));
+        let expand = |extra: &str| expand_file_path(base, extra, home());
+        assert_eq!(expand("~"), Path::new("/home/dev"));
+        assert_eq!(
+            expand("~/config/app.toml"),
+            Path::new("/home/dev/config/app.toml")
+        );
+        assert_eq!(expand("/etc/hosts"), Path::new("/etc/hosts"));
+        assert_eq!(expand("data/app.db"), Path::new("/srv/build/data/app.db"));
+        // Other users' homes are not looked up, and `~` inside a name is
+        // just a character.
+        assert_eq!(expand("~other/file"), Path::new("/srv/build/~other/file"));
+        assert_eq!(expand("backup~"), Path::new("/srv/build/backup~"));
+        // Without HOME the entry stays relative to the base.
+        assert_eq!(
+            expand_file_path(base, "~/config", None),
+            Path::new("/srv/build/~/config")
+        );
+    }
+
+    #[test]
+    fn private_libraries_are_found_through_origin_runpath() {
+        use crate::elf::tests::dynamic_elf;
+
+        let dir = TempDir::new("origin");
+        fs::create_dir_all(dir.0.join("bin/lib")).unwrap();
+        let app = dir.0.join("bin/app");
+        fs::write(
+            &app,
+            dynamic_elf(&["libpriv.so"], Some("$ORIGIN/lib"), None),
+        )
+        .unwrap();
+        fs::write(
+            dir.0.join("bin/lib/libpriv.so"),
+            dynamic_elf(&[], None, None),
+        )
+        .unwrap();
+
+        let mut deps = vec![("libpriv.so".to_string(), PathBuf::from("not found"))];
+        resolve_dst_deps(&app, &mut deps);
+        let lib = fs::canonicalize(dir.0.join("bin"))
+            .unwrap()
+            .join("lib/libpriv.so");
+        assert_eq!(deps, [("libpriv.so".to_string(), lib)]);
+    }
+
+    #[test]
+    fn replaced_targets_do_not_inherit_arch_or_capabilities() {
+        use crate::elf::tests::dynamic_elf;
+
+        let dir = TempDir::new("retarget");
+        let target = dir.0.join("new");
+        fs::write(&target, dynamic_elf(&["libc.so.6"], None, None)).unwrap();
+        let mut options = BundleOptions {
+            arch: Some("aarch64".into()),
+            capabilities: vec![("app".into(), vec![1, 2]), ("bins/helper".into(), vec![3])],
+            ..Default::default()
+        };
+
+        retarget_options(&mut options, &target, "app").unwrap();
+        assert_eq!(options.arch.as_deref(), Some("x86_64"));
+        assert_eq!(options.capabilities, [("bins/helper".to_string(), vec![3])]);
+    }
+}
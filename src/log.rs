@@ -0,0 +1,123 @@
+use std::env;
+use std::sync::OnceLock;
+
+const LOG_VAR: &str = "RUST_LOG";
+const CRATE_TARGET: &str = "rex";
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn parse(value: &str) -> Option<Self> {
+        Some(match value.to_ascii_lowercase().as_str() {
+            "off" => Self::Off,
+            "error" => Self::Error,
+            "warn" => Self::Warn,
+            "info" => Self::Info,
+            "debug" => Self::Debug,
+            "trace" => Self::Trace,
+            _ => return None,
+        })
+    }
+}
+
+static OVERRIDE: OnceLock<Level> = OnceLock::new();
+
+/// Caps output at `level` regardless of `RUST_LOG` (`--quiet`). Only
+/// effective before the first message is logged.
+pub fn set_max_level(level: Level) {
+    let _ = OVERRIDE.set(level);
+}
+
+// `RUST_LOG` in env_logger/tracing syntax: comma-separated `level` or
+// `target=level` directives, of which only bare ones and those for `rex`
+// apply. There are no per-module targets, so `rex::<module>` counts as
+// `rex`. A `rex` directive beats a bare one, the last wins among equals and
+// the default is `info`.
+fn filter_level(filter: &str) -> Level {
+    let mut best: Option<(bool, Level)> = None;
+    for directive in filter.split(',').map(str::trim) {
+        let (specific, level) = match directive.split_once('=') {
+            Some((target, level)) if target == CRATE_TARGET || target.starts_with("rex::") => {
+                (true, level)
+            }
+            Some(_) => continue,
+            None => (false, directive),
+        };
+        if let Some(level) = Level::parse(level)
+            && best.is_none_or(|(was_specific, _)| specific || !was_specific)
+        {
+            best = Some((specific, level));
+        }
+    }
+    best.map_or(Level::Info, |(_, level)| level)
+}
+
+fn max_level() -> Level {
+    static LEVEL: OnceLock<Level> = OnceLock::new();
+    *LEVEL.get_or_init(|| {
+        if let Some(level) = OVERRIDE.get() {
+            return *level;
+        }
+        filter_level(&env::var(LOG_VAR).unwrap_or_default())
+    })
+}
+
+pub fn enabled(level: Level) -> bool {
+    level != Level::Off && level <= max_level()
+}
+
+/// Build progress (`[Staging] ...`), printed to stdout at `info`.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Info) {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// `[Warning] ...` lines, printed to stdout at `warn`.
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Warn) {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Per-file detail, printed to stderr only with `RUST_LOG=rex=debug`.
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Debug) {
+            eprintln!("[debug] {}", format_args!($($arg)*));
+        }
+    };
+}
+
+pub(crate) use {debug, info, warning};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn most_specific_directive_wins() {
+        assert_eq!(filter_level(""), Level::Info);
+        assert_eq!(filter_level("debug"), Level::Debug);
+        assert_eq!(filter_level("rex=debug,warn"), Level::Debug);
+        assert_eq!(filter_level("warn,rex=debug"), Level::Debug);
+        assert_eq!(filter_level("rex::generator=trace,error"), Level::Trace);
+        assert_eq!(filter_level("rex=warn,rex=debug"), Level::Debug);
+        assert_eq!(filter_level("other=trace"), Level::Info);
+        assert_eq!(filter_level("rexx=trace,error"), Level::Error);
+        assert_eq!(filter_level("rex=bogus,warn"), Level::Warn);
+        assert_eq!(filter_level("rex=off"), Level::Off);
+    }
+}
@@ -1,90 +1,614 @@
+use crate::archive::{HeaderOverrides, TarFormat};
+use crate::bundle::{Codec, EmbedMode};
+use crate::checksum::Integrity;
+use crate::error::{Result, RexError};
+use crate::generator::{OutputFormat, SizeLimitOn};
 use crate::runtime::Runtime;
 use std::env;
-use std::error::Error;
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::str::FromStr;
 
+mod archive;
+mod bundle;
+mod checksum;
+mod elf;
+mod error;
+mod fsutil;
 mod generator;
+mod log;
+mod manifest;
 mod runtime;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const DEFAULT_COMPRESS: i32 = 5;
+const COMPRESS_ENV: &str = "REX_COMPRESSION_LEVEL";
 
 struct Cli {
     target_binary: Option<PathBuf>,
     compression_level: i32,
+    level_explicit: bool,
     extra_libs: Vec<PathBuf>,
     extra_bins: Vec<PathBuf>,
     additional_files: Vec<String>,
+    stdin_file: Option<PathBuf>,
+    entrypoint_script: Option<PathBuf>,
+    timeout: Option<u64>,
+    verify_after_build: bool,
+    store_patterns: Vec<String>,
+    store_compressed: bool,
+    files_base: Option<PathBuf>,
+    cache: bool,
+    no_path_prepend: bool,
+    min_compression_savings: Option<f64>,
+    resolve_target_name: bool,
+    target_name: Option<String>,
+    output_format: OutputFormat,
+    preserve_hardlinks: bool,
+    list_deps: bool,
+    json: bool,
+    append_to: Option<PathBuf>,
+    secure_cleanup: bool,
+    clean_env: bool,
+    clean_env_prefixes: Vec<String>,
+    keep_env: Vec<String>,
+    relink_rpath: bool,
+    no_loader_wrapper: bool,
+    stats_out: Option<PathBuf>,
+    overrides: HeaderOverrides,
+    follow_interpreter: bool,
+    benchmark: bool,
+    replace_target: Option<(PathBuf, PathBuf)>,
+    prune_unused_libs: bool,
+    strict_deps: bool,
+    output: Option<PathBuf>,
+    extension: Option<String>,
+    keep_payload: Option<PathBuf>,
+    dereference_data: bool,
+    strip: bool,
+    include_debug: bool,
+    integrity: Option<Integrity>,
+    no_recreate: bool,
+    post_extract: Option<String>,
+    install_dir: Option<String>,
+    install_command: Option<String>,
+    pidfile: Option<String>,
+    prepend_args: Vec<String>,
+    loader_args: Vec<String>,
+    toolbox: Option<PathBuf>,
+    dedup_store: Option<PathBuf>,
+    check_only: bool,
+    sysroot: Option<PathBuf>,
+    max_deps: Option<usize>,
+    min_glibc: Option<String>,
+    size_limit: Option<u64>,
+    size_limit_on: SizeLimitOn,
+    embed_mode: Option<EmbedMode>,
+    tar_format: TarFormat,
+    no_extra_libs_deps: bool,
+    time_budget: Option<u64>,
 }
 
 impl Cli {
-    fn parse() -> Result<Self, Box<dyn Error>> {
-        let mut args = env::args().skip(1);
-        if args.len() == 0 {
-            return Err(Cli::print_help().into());
+    fn parse() -> Result<Self> {
+        let mut args: Vec<String> = env::args().skip(1).collect();
+        if args.is_empty() {
+            return Err(RexError::Usage(Cli::print_help()));
         }
 
+        // Manifest values come first so that any flag given on the command
+        // line overrides them (or, for repeatable flags, adds to them).
+        if let Some(i) = args.iter().position(|a| a == "--manifest-in") {
+            let path = args
+                .get(i + 1)
+                .cloned()
+                .ok_or_else(|| RexError::Usage("Missing value".into()))?;
+            args.drain(i..i + 2);
+            args.splice(0..0, manifest::to_args(Path::new(&path))?);
+        }
+        let mut args = args.into_iter();
+
+        // -L wins over REX_COMPRESSION_LEVEL, which wins over the default.
+        let mut level = None;
         let mut cli = Self {
             target_binary: None,
             compression_level: DEFAULT_COMPRESS,
+            level_explicit: false,
             extra_libs: vec![],
             extra_bins: vec![],
             additional_files: vec![],
+            stdin_file: None,
+            entrypoint_script: None,
+            timeout: None,
+            verify_after_build: false,
+            store_patterns: vec![],
+            store_compressed: false,
+            files_base: None,
+            cache: false,
+            no_path_prepend: false,
+            min_compression_savings: None,
+            resolve_target_name: false,
+            target_name: None,
+            output_format: OutputFormat::Rex,
+            preserve_hardlinks: false,
+            list_deps: false,
+            json: false,
+            append_to: None,
+            secure_cleanup: false,
+            clean_env: false,
+            clean_env_prefixes: vec![],
+            keep_env: vec![],
+            relink_rpath: false,
+            no_loader_wrapper: false,
+            stats_out: None,
+            overrides: HeaderOverrides::default(),
+            follow_interpreter: false,
+            benchmark: false,
+            replace_target: None,
+            prune_unused_libs: false,
+            strict_deps: false,
+            output: None,
+            extension: None,
+            keep_payload: None,
+            dereference_data: false,
+            strip: false,
+            include_debug: false,
+            integrity: None,
+            no_recreate: false,
+            post_extract: None,
+            install_dir: None,
+            install_command: None,
+            pidfile: None,
+            prepend_args: vec![],
+            loader_args: vec![],
+            toolbox: None,
+            dedup_store: None,
+            check_only: false,
+            sysroot: None,
+            max_deps: None,
+            min_glibc: None,
+            size_limit: None,
+            size_limit_on: SizeLimitOn::Compressed,
+            embed_mode: None,
+            tar_format: TarFormat::default(),
+            no_extra_libs_deps: false,
+            time_budget: None,
         };
 
         while let Some(arg) = args.next() {
             match arg.as_str() {
                 "-t" => cli.target_binary = Some(Self::expect_path(&mut args)?),
-                "-L" => cli.compression_level = Self::expect_value(&mut args)?.parse()?,
+                "-L" => level = Some(Self::expect_value(&mut args)?),
+                "--time-budget" => cli.time_budget = Some(Self::expect_num(&mut args, &arg)?),
                 "-l" => cli.extra_libs.push(Self::expect_path(&mut args)?),
+                "--no-extra-libs-deps" => cli.no_extra_libs_deps = true,
                 "-b" => cli.extra_bins.push(Self::expect_path(&mut args)?),
+                "--extra-bin-name" => {
+                    let name = Self::expect_value(&mut args)?;
+                    let path = fsutil::which(&name).filter(|_| !name.contains('/'));
+                    cli.extra_bins.push(path.ok_or_else(|| {
+                        RexError::Usage(format!("--extra-bin-name: '{name}' not found in PATH"))
+                    })?);
+                }
                 "-f" => cli.additional_files.push(Self::expect_value(&mut args)?),
-                _ => return Err(Cli::print_help().into()),
+                "-o" => cli.output = Some(Self::expect_path(&mut args)?),
+                "--no-extension" => cli.extension = Some(String::new()),
+                "--extension" => {
+                    // `bin` means `.bin`; suffixes such as `-linux-x86_64`
+                    // are kept as given.
+                    let ext = Self::expect_value(&mut args)?;
+                    cli.extension = Some(if ext.starts_with(|c: char| c.is_alphanumeric()) {
+                        format!(".{ext}")
+                    } else {
+                        ext
+                    });
+                }
+                "--stdin-file" => cli.stdin_file = Some(Self::expect_path(&mut args)?),
+                "--entrypoint-script" => {
+                    cli.entrypoint_script = Some(Self::expect_path(&mut args)?)
+                }
+                "--timeout" => cli.timeout = Some(Self::expect_num(&mut args, &arg)?),
+                "--verify-after-build" => cli.verify_after_build = true,
+                "--store" => cli.store_patterns.push(Self::expect_value(&mut args)?),
+                "--store-compressed" => cli.store_compressed = true,
+                "--dedup-across-bundles" => cli.dedup_store = Some(Self::expect_path(&mut args)?),
+                "--dereference-data" => cli.dereference_data = true,
+                "--preserve-symlinks-data" => cli.dereference_data = false,
+                "--files-base" => cli.files_base = Some(Self::expect_path(&mut args)?),
+                "--concurrency-safe-cache" => cli.cache = true,
+                "--no-path-prepend" => cli.no_path_prepend = true,
+                "--resolve-target-name" => cli.resolve_target_name = true,
+                "--target-name" => cli.target_name = Some(Self::expect_value(&mut args)?),
+                "--preserve-hardlinks" => cli.preserve_hardlinks = true,
+                "--list-deps" => cli.list_deps = true,
+                "--check-only" => cli.check_only = true,
+                "--sysroot" => cli.sysroot = Some(Self::expect_path(&mut args)?),
+                "--json" => cli.json = true,
+                "--quiet" | "-q" => log::set_max_level(log::Level::Warn),
+                "--benchmark" => cli.benchmark = true,
+                "--no-recreate" => cli.no_recreate = true,
+                "--post-extract" => {
+                    let hook = Self::expect_value(&mut args)?;
+                    if hook.contains('\0') {
+                        return Err(RexError::Usage("Invalid --post-extract command".into()));
+                    }
+                    cli.post_extract = Some(hook);
+                }
+                "--installer-mode" => cli.install_dir = Some(Self::expect_option(&mut args, &arg)?),
+                "--installer-command" => {
+                    cli.install_command = Some(Self::expect_option(&mut args, &arg)?)
+                }
+                "--pidfile" => cli.pidfile = Some(Self::expect_option(&mut args, &arg)?),
+                "--target-glibc" | "--min-glibc" => {
+                    let version = Self::expect_value(&mut args)?;
+                    if elf::parse_glibc_version(&version).is_none() {
+                        return Err(RexError::Usage(format!(
+                            "Invalid value for {arg}: '{version}' (expected e.g. 2.31)"
+                        )));
+                    }
+                    cli.min_glibc = Some(version);
+                }
+                "--loader-option" => {
+                    let option = Self::expect_value(&mut args)?;
+                    cli.loader_args.extend(Self::loader_option(&option)?);
+                }
+                "--toolbox" => cli.toolbox = Some(Self::expect_path(&mut args)?),
+                "--prepend-arg" => {
+                    let value = Self::expect_value(&mut args)?;
+                    cli.prepend_args.push(Self::check_baked_arg(value)?);
+                }
+                "--entrypoint-args-file" => {
+                    let path = Self::expect_path(&mut args)?;
+                    let text = fs::read_to_string(&path).map_err(|e| {
+                        RexError::Usage(format!("Cannot read {}: {e}", path.display()))
+                    })?;
+                    for line in text.lines() {
+                        cli.prepend_args
+                            .push(Self::check_baked_arg(line.to_string())?);
+                    }
+                }
+                "--max-deps" => cli.max_deps = Some(Self::expect_num(&mut args, &arg)?),
+                "--size-limit" => cli.size_limit = Some(Self::expect_size(&mut args, &arg)?),
+                "--size-limit-on" => {
+                    cli.size_limit_on = SizeLimitOn::parse(&Self::expect_value(&mut args)?)?
+                }
+                "--tar-format" => {
+                    cli.tar_format = TarFormat::parse(&Self::expect_value(&mut args)?)?
+                }
+                "--embed-mode" => {
+                    cli.embed_mode = Some(EmbedMode::parse(&Self::expect_value(&mut args)?)?)
+                }
+                "--replace-target" => {
+                    let new_target = Self::expect_path(&mut args)?;
+                    cli.replace_target = Some((new_target, Self::expect_path(&mut args)?));
+                }
+                "--prune-unused-libs" | "--prune" => cli.prune_unused_libs = true,
+                "--follow-interpreter" => cli.follow_interpreter = true,
+                "--strict-deps" | "--strict" => cli.strict_deps = true,
+                "--strip" => cli.strip = true,
+                "--integrity" => {
+                    cli.integrity = Some(Integrity::parse(&Self::expect_value(&mut args)?)?)
+                }
+                "--include-debug" => cli.include_debug = true,
+                "--owner" => cli.overrides.owner = Some(Self::expect_owner(&mut args)?),
+                "--mode" => cli.overrides.mode = Some(Self::expect_mode(&mut args)?),
+                "--reproducible" => cli.overrides.mtime = Some(0),
+                "--stats-out" => cli.stats_out = Some(Self::expect_path(&mut args)?),
+                "--keep-payload" => cli.keep_payload = Some(Self::expect_path(&mut args)?),
+                "--secure-cleanup" => cli.secure_cleanup = true,
+                "--clean-env" => cli.clean_env = true,
+                "--clean-env-prefix" => cli.clean_env_prefixes.push(Self::expect_value(&mut args)?),
+                "--relink-rpath" => cli.relink_rpath = true,
+                "--no-loader-wrapper" => cli.no_loader_wrapper = true,
+                "--keep-env" => cli.keep_env.push(Self::expect_value(&mut args)?),
+                "--append-to" => cli.append_to = Some(Self::expect_path(&mut args)?),
+                "--output-format" => {
+                    cli.output_format = OutputFormat::parse(&Self::expect_value(&mut args)?)?
+                }
+                "--min-compression-savings" => {
+                    cli.min_compression_savings = Some(Self::expect_num(&mut args, &arg)?)
+                }
+                _ => return Err(RexError::Usage(Cli::print_help())),
             }
         }
 
+        let codec = Codec::Zstd;
+        if level.is_some() && cli.time_budget.is_some() {
+            return Err(RexError::Usage(
+                "-L and --time-budget cannot be combined".into(),
+            ));
+        }
+        if let Some(level) = level {
+            cli.compression_level = codec.level(&level)?;
+            cli.level_explicit = true;
+        } else if let Ok(level) = env::var(COMPRESS_ENV) {
+            cli.compression_level = codec
+                .level(&level)
+                .map_err(|e| RexError::Usage(format!("Invalid {COMPRESS_ENV}: {e}")))?;
+            cli.level_explicit = true;
+        }
+
         Ok(cli)
     }
 
-    fn expect_value(args: &mut impl Iterator<Item = String>) -> Result<String, Box<dyn Error>> {
-        args.next().ok_or("Missing value".into())
+    fn expect_value(args: &mut impl Iterator<Item = String>) -> Result<String> {
+        args.next()
+            .ok_or_else(|| RexError::Usage("Missing value".into()))
+    }
+
+    fn expect_num<T: FromStr>(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<T> {
+        let value = Self::expect_value(args)?;
+        value
+            .parse()
+            .map_err(|_| RexError::Usage(format!("Invalid value for {flag}: '{value}'")))
+    }
+
+    // Values baked into the bundle options, whose records are NUL-separated.
+    fn expect_option(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<String> {
+        let value = Self::expect_value(args)?;
+        if value.is_empty() || value.contains('\0') {
+            return Err(RexError::Usage(format!(
+                "Invalid value for {flag}: '{value}'"
+            )));
+        }
+        Ok(value)
     }
 
-    fn expect_path(args: &mut impl Iterator<Item = String>) -> Result<PathBuf, Box<dyn Error>> {
+    // Only options that narrow what the glibc loader may load are accepted,
+    // so baked metadata cannot smuggle in e.g. `--preload` or `--audit`.
+    fn loader_option(option: &str) -> Result<Vec<String>> {
+        const FLAGS: &[&str] = &["--inhibit-cache"];
+        const WITH_VALUE: &[&str] = &["--inhibit-rpath", "--glibc-hwcaps-mask"];
+        let (name, value) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (option, None),
+        };
+        match value {
+            None if FLAGS.contains(&name) => Ok(vec![name.to_string()]),
+            Some(value)
+                if WITH_VALUE.contains(&name) && !value.is_empty() && !value.contains('\0') =>
+            {
+                Ok(vec![name.to_string(), value.to_string()])
+            }
+            _ => Err(RexError::Usage(format!(
+                "Unsupported --loader-option '{option}' (allowed: --inhibit-cache, \
+                 --inhibit-rpath=<libs>, --glibc-hwcaps-mask=<names>)"
+            ))),
+        }
+    }
+
+    fn check_baked_arg(arg: String) -> Result<String> {
+        if arg.contains('\0') {
+            return Err(RexError::Usage(format!("Invalid baked argument: '{arg}'")));
+        }
+        Ok(arg)
+    }
+
+    fn expect_path(args: &mut impl Iterator<Item = String>) -> Result<PathBuf> {
         Ok(PathBuf::from(Self::expect_value(args)?))
     }
 
+    fn expect_owner(args: &mut impl Iterator<Item = String>) -> Result<(u32, u32)> {
+        let value = Self::expect_value(args)?;
+        value
+            .split_once(':')
+            .and_then(|(uid, gid)| Some((uid.parse().ok()?, gid.parse().ok()?)))
+            .ok_or_else(|| RexError::Usage(format!("Invalid value for --owner: '{value}'")))
+    }
+
+    fn expect_mode(args: &mut impl Iterator<Item = String>) -> Result<u32> {
+        let value = Self::expect_value(args)?;
+        u32::from_str_radix(value.trim_start_matches("0o"), 8)
+            .ok()
+            .filter(|mode| *mode <= 0o7777)
+            .ok_or_else(|| RexError::Usage(format!("Invalid value for --mode: '{value}'")))
+    }
+
+    // Plain bytes or a number with a K/M/G/T suffix (powers of 1024), e.g.
+    // `50M`, `1.5GiB`.
+    fn expect_size(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<u64> {
+        let value = Self::expect_value(args)?;
+        let upper = value.to_ascii_uppercase();
+        let digits = upper.trim_end_matches("IB").trim_end_matches('B');
+        let (number, shift) = match digits.char_indices().last() {
+            Some((i, 'K')) => (&digits[..i], 10),
+            Some((i, 'M')) => (&digits[..i], 20),
+            Some((i, 'G')) => (&digits[..i], 30),
+            Some((i, 'T')) => (&digits[..i], 40),
+            _ => (digits, 0),
+        };
+        number
+            .parse::<f64>()
+            .ok()
+            .filter(|n| n.is_finite() && *n >= 0.0)
+            .map(|n| (n * (1u64 << shift) as f64) as u64)
+            .ok_or_else(|| RexError::Usage(format!("Invalid value for {flag}: '{value}'")))
+    }
+
+    fn into_bundle_args(self, target_binary: PathBuf) -> generator::BundleArgs {
+        generator::BundleArgs {
+            target_binary,
+            compression_level: self.compression_level,
+            level_explicit: self.level_explicit,
+            extra_libs: self.extra_libs,
+            extra_bins: self.extra_bins,
+            additional_files: self.additional_files,
+            stdin_file: self.stdin_file,
+            entrypoint_script: self.entrypoint_script,
+            timeout: self.timeout,
+            verify_after_build: self.verify_after_build,
+            store_patterns: self.store_patterns,
+            store_compressed: self.store_compressed,
+            files_base: self.files_base,
+            cache: self.cache,
+            no_path_prepend: self.no_path_prepend,
+            min_compression_savings: self.min_compression_savings,
+            resolve_target_name: self.resolve_target_name,
+            target_name: self.target_name,
+            output_format: self.output_format,
+            preserve_hardlinks: self.preserve_hardlinks,
+            secure_cleanup: self.secure_cleanup,
+            clean_env: self.clean_env,
+            clean_env_prefixes: self.clean_env_prefixes,
+            keep_env: self.keep_env,
+            relink_rpath: self.relink_rpath,
+            no_loader_wrapper: self.no_loader_wrapper,
+            stats_out: self.stats_out,
+            overrides: self.overrides,
+            follow_interpreter: self.follow_interpreter,
+            benchmark: self.benchmark,
+            json: self.json,
+            prune_unused_libs: self.prune_unused_libs,
+            strict_deps: self.strict_deps,
+            output: self.output,
+            extension: self.extension,
+            keep_payload: self.keep_payload,
+            dereference_data: self.dereference_data,
+            strip: self.strip,
+            include_debug: self.include_debug,
+            integrity: self.integrity,
+            no_recreate: self.no_recreate,
+            post_extract: self.post_extract,
+            install_dir: self.install_dir,
+            install_command: self.install_command,
+            pidfile: self.pidfile,
+            prepend_args: self.prepend_args,
+            loader_args: self.loader_args,
+            toolbox: self.toolbox,
+            dedup_store: self.dedup_store,
+            check_only: self.check_only,
+            sysroot: self.sysroot,
+            max_deps: self.max_deps,
+            min_glibc: self.min_glibc,
+            size_limit: self.size_limit,
+            size_limit_on: self.size_limit_on,
+            embed_mode: self.embed_mode,
+            tar_format: self.tar_format,
+            no_extra_libs_deps: self.no_extra_libs_deps,
+            time_budget: self.time_budget,
+        }
+    }
+
     fn print_help() -> String {
         format!(
             "Rex {VERSION} - static Rust EXecutable generator and runtime\n
 Usage: rex <options>\n
 Options:
-  -t <file>  Path to the main target binary to bundle
-  -L <num>   Compression level (1–22, default {DEFAULT_COMPRESS})
-  -l <file>  Additional libraries to include
-  -b <file>  Additional binaries to include
-  -f <path>  Extra files or folders to include"
+  -t <file>              Path to the main target binary to bundle
+                         (- reads it from stdin, http(s):// URLs are downloaded)
+  -L <num|preset>        Compression level (1–22 or fast/default/best,
+                         default ${COMPRESS_ENV} or {DEFAULT_COMPRESS})
+  -l <file>              Additional libraries to include
+  --no-extra-libs-deps   Stage -l libraries without resolving their dependencies
+  -b <file>              Additional binaries to include
+  --toolbox <dir>        Bundle every executable in <dir>, picked by name at launch
+  --extra-bin-name <name>
+                         Like -b, for an executable looked up in PATH
+  -f <path>              Extra files or folders to include
+  -o <file>              Write the bundle to <file> (default <target>.Rex)
+  --extension <ext>      Use <ext> instead of .Rex when -o is not given
+  --no-extension         Name the bundle exactly like the target
+  --manifest-in <file>   Read options from a TOML manifest (flags override it)
+  --stdin-file <file>    Feed the bundled program's stdin from <file>
+  --entrypoint-script <file>
+                         Run <file> with sh at launch; it execs the target itself
+  --timeout <secs>       Kill the bundled program after <secs> seconds
+  --integrity <mode>     Payload check at launch: none, crc32 (default) or sha256
+  --verify-after-build   Re-open and check the bundle once it is written
+  --store <glob>         Keep matching extra files (-f) out of compression
+  --store-compressed     Keep already-compressed extra files out of compression
+  --dedup-across-bundles <dir>
+                         Keep libs in a shared store <dir> instead of the bundle
+  --dereference-data     Bundle the targets of symlinked -f files, not the links
+  --preserve-symlinks-data
+                         Bundle symlinked -f files as links (default)
+  --files-base <dir>     Resolve relative -f paths against <dir> (default: CWD)
+  --concurrency-safe-cache
+                         Extract once into a shared cache and reuse it
+  --no-path-prepend      Append bins/ to PATH so host tools take precedence
+  --min-compression-savings <pct>
+                         Warn when compression saves less than <pct> percent
+  --resolve-target-name  Name the bundle after the real file when -t is a symlink
+  --target-name <name>   Name the bundled program <name> regardless of -t
+  --output-format <fmt>  rex (self-extracting, default) or rootfs (FHS tree)
+  --preserve-hardlinks   Stage hardlinked extra libs/files as hardlinks
+  --list-deps            Print the resolved dependencies of -t and exit
+  --check-only           Validate the inputs, report problems and exit
+  --json                 Print machine-readable JSON output
+  -q, --quiet            Only print warnings, errors and the final summary
+  --follow-interpreter   Bundle the interpreters of #! scripts given with -f
+  --owner <uid:gid>      Record every payload entry as owned by <uid:gid>
+  --mode <octal>         Record every payload entry with mode <octal> (+x kept)
+  --reproducible         Record zero mtimes instead of the files' own
+  --prune-unused-libs    Drop staged libs that no bundled binary needs
+  --strip                Strip staged binaries and libs (not the loader)
+  --include-debug        With --strip, keep their debug info under debug/
+  --strict-deps          Fail instead of warn on dependency problems (glibc
+                         mismatch, conflicting libs, failed copies)
+  --no-recreate          Merge into an existing staging dir instead of wiping it
+  --time-budget <secs>   Pick the highest level expected to compress within <secs>
+  --benchmark            Report size and time at levels 3/9/15/19 before building
+  --stats-out <file>     Write build size/timing statistics as JSON to <file>
+  --keep-payload <file>  Also save the bare .tar.zstd payload to <file>
+  --max-deps <n>         Abort if more than <n> unique libraries are resolved
+  --size-limit <size>    Abort if the bundle exceeds <size> (bytes or 50M, 1G, ...)
+  --size-limit-on <what> Check compressed (default) or uncompressed size
+  --embed-mode <mode>    Append the payload (default) or put it in an ELF section
+  --tar-format <format>  Write pax (default), strict ustar or GNU TAR headers
+  --append-to <bundle>   Add or replace -f files in an existing .Rex bundle
+  --replace-target <new> <bundle>
+                         Swap the target of an existing .Rex for <new>
+  --post-extract <cmd>   Run <cmd> with sh in the extracted tree before the target
+  --installer-mode <dir> Make `<bundle> install` extract the payload to <dir>
+  --installer-command <word>
+                         First argument that triggers installing (default install)
+  --pidfile <path>       Write the running target's PID to <path> on every launch
+  --sysroot <dir>        Resolve libraries and the loader from <dir> (e.g. musl)
+  --target-glibc <ver>   Refuse to run on hosts with a glibc older than <ver>
+  --prepend-arg <arg>    Pass <arg> to the target before the launch arguments
+  --loader-option <opt>  Pass --inhibit-cache (or an allowed option=value) to the loader
+  --entrypoint-args-file <file>
+                         Like --prepend-arg for every line of <file>
+  --secure-cleanup       Zero extracted files before deleting them on exit
+  --clean-env            Strip host LD_*, GTK_* and PYTHON* variables at launch
+  --clean-env-prefix <p> Strip variables starting with <p> instead (repeatable)
+  --keep-env <VAR>       Never strip <VAR> in clean environment mode
+  --relink-rpath         Point staged binaries at the bundled libs (patchelf)
+  --no-loader-wrapper    Run a relinked target directly instead of via the loader"
         )
     }
 }
 
-fn rex_main(runtime: &mut Runtime) -> Result<(), Box<dyn Error>> {
+fn rex_main(runtime: &mut Runtime) -> Result<()> {
     if runtime.is_bundled() {
         return runtime.run();
     }
 
     let cli = Cli::parse()?;
 
-    let args = generator::BundleArgs {
-        target_binary: cli.target_binary.ok_or("Error: -t <file> is required")?,
-        compression_level: cli.compression_level,
-        extra_libs: cli.extra_libs,
-        extra_bins: cli.extra_bins,
-        additional_files: cli.additional_files,
-    };
+    if cli.list_deps {
+        let target = cli
+            .target_binary
+            .ok_or_else(|| RexError::Usage("Error: -t <file> is required".into()))?;
+        return generator::list_deps(&target, cli.json);
+    }
+
+    if let Some((new_target, bundle)) = cli.replace_target.clone() {
+        let args = cli.into_bundle_args(PathBuf::new());
+        return generator::replace_target(&bundle, &new_target, args);
+    }
+
+    if let Some(bundle) = cli.append_to.clone() {
+        return generator::append_files(&bundle, cli.into_bundle_args(PathBuf::new()));
+    }
 
-    generator::generate_bundle(args)
+    let target = cli
+        .target_binary
+        .clone()
+        .or(cli.toolbox.as_ref().map(|_| PathBuf::new()))
+        .ok_or_else(|| RexError::Usage("Error: -t <file> is required".into()))?;
+    generator::generate_bundle(cli.into_bundle_args(target))
 }
 
 fn main() {
@@ -95,7 +619,7 @@ fn main() {
                 if !runtime.has_run() {
                     eprintln!("{e}");
                 }
-                1
+                runtime.exit_code().unwrap_or(1)
             }
         },
         Err(e) => {
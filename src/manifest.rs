@@ -0,0 +1,199 @@
+use crate::error::{Result, RexError};
+use std::fs;
+use std::path::Path;
+
+// Manifest key, the CLI flag it stands for, and whether the flag takes a value.
+const KEYS: &[(&str, &str, bool)] = &[
+    ("target", "-t", true),
+    ("level", "-L", true),
+    ("time_budget", "--time-budget", true),
+    ("libs", "-l", true),
+    ("no_extra_libs_deps", "--no-extra-libs-deps", false),
+    ("bins", "-b", true),
+    ("bin_names", "--extra-bin-name", true),
+    ("toolbox", "--toolbox", true),
+    ("files", "-f", true),
+    ("output", "-o", true),
+    ("extension", "--extension", true),
+    ("no_extension", "--no-extension", false),
+    ("stdin_file", "--stdin-file", true),
+    ("entrypoint_script", "--entrypoint-script", true),
+    ("timeout", "--timeout", true),
+    ("integrity", "--integrity", true),
+    ("verify_after_build", "--verify-after-build", false),
+    ("store", "--store", true),
+    ("store_compressed", "--store-compressed", false),
+    ("dedup_store", "--dedup-across-bundles", true),
+    ("files_base", "--files-base", true),
+    ("dereference_data", "--dereference-data", false),
+    ("cache", "--concurrency-safe-cache", false),
+    ("no_path_prepend", "--no-path-prepend", false),
+    ("min_compression_savings", "--min-compression-savings", true),
+    ("resolve_target_name", "--resolve-target-name", false),
+    ("target_name", "--target-name", true),
+    ("output_format", "--output-format", true),
+    ("preserve_hardlinks", "--preserve-hardlinks", false),
+    ("follow_interpreter", "--follow-interpreter", false),
+    ("owner", "--owner", true),
+    ("mode", "--mode", true),
+    ("reproducible", "--reproducible", false),
+    ("prune_unused_libs", "--prune-unused-libs", false),
+    ("strict_deps", "--strict-deps", false),
+    ("strip", "--strip", false),
+    ("include_debug", "--include-debug", false),
+    ("no_recreate", "--no-recreate", false),
+    ("stats_out", "--stats-out", true),
+    ("quiet", "--quiet", false),
+    ("keep_payload", "--keep-payload", true),
+    ("max_deps", "--max-deps", true),
+    ("size_limit", "--size-limit", true),
+    ("size_limit_on", "--size-limit-on", true),
+    ("embed_mode", "--embed-mode", true),
+    ("tar_format", "--tar-format", true),
+    ("post_extract", "--post-extract", true),
+    ("installer_mode", "--installer-mode", true),
+    ("installer_command", "--installer-command", true),
+    ("pidfile", "--pidfile", true),
+    ("target_glibc", "--target-glibc", true),
+    ("sysroot", "--sysroot", true),
+    ("prepend_args", "--prepend-arg", true),
+    ("loader_options", "--loader-option", true),
+    ("entrypoint_args_file", "--entrypoint-args-file", true),
+    ("secure_cleanup", "--secure-cleanup", false),
+    ("clean_env", "--clean-env", false),
+    ("clean_env_prefixes", "--clean-env-prefix", true),
+    ("keep_env", "--keep-env", true),
+    ("relink_rpath", "--relink-rpath", false),
+    ("no_loader_wrapper", "--no-loader-wrapper", false),
+];
+
+#[derive(Debug)]
+enum Value {
+    Str(String),
+    Bool(bool),
+    List(Vec<Value>),
+}
+
+/// Reads a TOML manifest and translates it into the equivalent CLI arguments,
+/// so it goes through exactly the same parsing and validation as flags do.
+/// Only the subset needed here is understood: `key = value` pairs with
+/// strings, numbers, booleans and (possibly multi-line) arrays.
+pub fn to_args(path: &Path) -> Result<Vec<String>> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| RexError::Usage(format!("Cannot read manifest {}: {e}", path.display())))?;
+    let err = |line: usize, msg: &str| RexError::Usage(format!("{}:{line}: {msg}", path.display()));
+
+    let mut args = vec![];
+    let mut lines = text.lines().enumerate();
+    while let Some((n, line)) = lines.next() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('[') {
+            return Err(err(n + 1, "tables are not supported"));
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(err(n + 1, "expected key = value"));
+        };
+        let key = key.trim();
+        let mut value = value.trim().to_string();
+        // Multi-line arrays: keep reading until the brackets balance.
+        while value.starts_with('[') && !value.ends_with(']') {
+            let Some((_, next)) = lines.next() else {
+                return Err(err(n + 1, "unterminated array"));
+            };
+            value.push_str(strip_comment(next).trim());
+        }
+
+        let &(_, flag, takes_value) = KEYS
+            .iter()
+            .find(|(k, _, _)| *k == key)
+            .ok_or_else(|| err(n + 1, &format!("unknown key '{key}'")))?;
+        let value = parse_value(&value).ok_or_else(|| err(n + 1, "invalid value"))?;
+        let items = match value {
+            Value::List(items) => items,
+            value => vec![value],
+        };
+        for item in items {
+            match (item, takes_value) {
+                (Value::Bool(true), false) => args.push(flag.to_string()),
+                (Value::Bool(false), false) => {}
+                (Value::Str(s), true) => args.extend([flag.to_string(), s]),
+                _ => return Err(err(n + 1, &format!("wrong value type for '{key}'"))),
+            }
+        }
+    }
+    Ok(args)
+}
+
+// Drops a trailing `# comment`, ignoring `#` inside strings.
+fn strip_comment(line: &str) -> &str {
+    let mut in_str = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_str => escaped = true,
+            '"' => in_str = !in_str,
+            '#' if !in_str => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_value(text: &str) -> Option<Value> {
+    let (value, rest) = parse_one(text.trim())?;
+    rest.trim().is_empty().then_some(value)
+}
+
+fn parse_one(text: &str) -> Option<(Value, &str)> {
+    if let Some(rest) = text.strip_prefix('"') {
+        let mut out = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Some((Value::Str(out), &rest[i + 1..])),
+                '\\' => out.push(match chars.next()?.1 {
+                    'n' => '\n',
+                    't' => '\t',
+                    c @ ('"' | '\\') => c,
+                    _ => return None,
+                }),
+                c => out.push(c),
+            }
+        }
+        return None;
+    }
+
+    if let Some(mut rest) = text.strip_prefix('[') {
+        let mut items = vec![];
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix(']') {
+                return Some((Value::List(items), after));
+            }
+            let (item, after) = parse_one(rest)?;
+            if matches!(item, Value::List(_)) {
+                return None;
+            }
+            items.push(item);
+            rest = after.trim_start();
+            rest = rest.strip_prefix(',').unwrap_or(rest);
+        }
+    }
+
+    let end = text
+        .find(|c: char| c == ',' || c == ']' || c.is_whitespace())
+        .unwrap_or(text.len());
+    let (word, rest) = text.split_at(end);
+    match word {
+        "true" => Some((Value::Bool(true), rest)),
+        "false" => Some((Value::Bool(false), rest)),
+        _ if !word.is_empty() && word.parse::<f64>().is_ok() => {
+            Some((Value::Str(word.to_string()), rest))
+        }
+        _ => None,
+    }
+}
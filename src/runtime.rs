@@ -1,60 +1,306 @@
-use std::error::Error;
+use crate::archive::{self, EntryKind};
+use crate::bundle::{
+    self, BundleMetadata, BundleOptions, CAPABILITY_XATTR, FORMAT_VERSION, Fnv64, HashingReader,
+    LEGACY_METADATA_SIZE, MAGIC_MARKER,
+};
+use crate::checksum::{Digest, Integrity};
+use crate::elf::{self, Elf};
+use crate::error::{Result, RexError};
+use crate::fsutil;
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{self, IsTerminal, Read, Seek, SeekFrom, Write};
 use std::mem::size_of;
-use std::path::Path;
-use std::process::Command;
-use std::{env, fs};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+use std::{env, fs, thread};
 
-const MAGIC_MARKER: [u8; 10] = *b"REX_BUNDLE";
+const TIMEOUT_EXIT_CODE: i32 = 124;
+const LOADER_FAILURE_EXIT_CODE: i32 = 127;
+const SIGTERM: i32 = 15;
+const KILL_GRACE: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const PROC_SELF_EXE: &str = "/proc/self/exe";
+const QEMU_VAR: &str = "REX_QEMU";
+const CLEAN_ENV_VAR: &str = "REX_CLEAN_ENV";
+const LIB_FALLBACK_VAR: &str = "REX_LIB_FALLBACK";
+const RUN_WITH_PREFIX: &str = "--rex-run-with=";
+const NO_CACHE_VAR: &str = "REX_NO_CACHE";
+const PRELOAD_CHECK_VAR: &str = "REX_PRELOAD_CHECK";
+const FALLBACK_HOST_LOADER_VAR: &str = "REX_FALLBACK_HOST_LOADER";
+const QUIET_VAR: &str = "REX_QUIET";
+const PIDFILE_VAR: &str = "REX_PIDFILE";
+const DEDUP_STORE_VAR: &str = "REX_DEDUP_STORE";
+const EXTRA_LIBPATH_VAR: &str = "REX_EXTRA_LIBPATH";
+const PROGRESS_MIN_SIZE: u64 = 64 << 20;
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+// SIGILL, SIGBUS, SIGSEGV: how an incompatible loader typically dies.
+const LOADER_CRASH_SIGNALS: &[i32] = &[4, 7, 11];
+const ARGV0_GLIBC: (u32, u32) = (2, 33);
+const COMPLETE_MARKER: &str = ".rex_complete";
+const HOST_LIB_DIRS: &[&str] = &["/lib64", "/usr/lib64", "/lib", "/usr/lib"];
+const DEFAULT_CLEAN_PREFIXES: &[&str] = &["LD_", "GTK_", "PYTHON"];
 
-#[repr(C, packed)]
-struct BundleMetadata {
-    payload_size: u64,
-    target_bin_name_len: u32,
+unsafe extern "C" {
+    fn kill(pid: i32, sig: i32) -> i32;
 }
 
-const _: () = assert!(size_of::<BundleMetadata>() == 12);
+/// Where a launch extracted its payload and what to do with it on exit.
+struct Extraction {
+    /// The `<target>_bundle` directory the program runs in.
+    dir: PathBuf,
+    /// What cleanup removes: `dir` itself or the directory holding it.
+    root: PathBuf,
+    /// Shared while running; the last launch to leave removes `root`.
+    lock: Option<fsutil::FileLock>,
+    keep: bool,
+}
 
 struct PayloadInfo {
+    source: PathBuf,
     metadata: BundleMetadata,
     payload_start_offset: u64,
     target_binary_name: String,
+    options: BundleOptions,
+}
+
+/// Public view of a bundle's trailer, for programs that embed the runtime and
+/// want to inspect a bundle before deciding whether to run it.
+#[derive(Debug, Clone)]
+pub struct BundleInfo {
+    /// File the trailer was read from.
+    pub source: PathBuf,
+    /// Name of the bundled program, also used for its extraction directory.
+    pub target_name: String,
+    /// Offset of the Zstd payload from the start of `source`.
+    pub payload_offset: u64,
+    /// Size of the Zstd payload in bytes.
+    pub payload_size: u64,
+    /// Options baked in at build time (timeout, store size, hash, ...).
+    pub options: BundleOptions,
+}
+
+impl From<&PayloadInfo> for BundleInfo {
+    fn from(info: &PayloadInfo) -> Self {
+        Self {
+            source: info.source.clone(),
+            target_name: info.target_binary_name.clone(),
+            payload_offset: info.payload_start_offset,
+            payload_size: info.metadata.payload_size,
+            options: info.options.clone(),
+        }
+    }
+}
+
+/// Counts the TAR bytes unpacked from a large payload and keeps a
+/// `[rex] Extracting ... NN%` line up to date on stderr, erasing it on drop.
+struct Progress<R: Read> {
+    inner: R,
+    done: u64,
+    total: u64,
+    shown: Option<Instant>,
+}
+
+impl<R: Read> Progress<R> {
+    // Only a terminal gets the line; pipes and logs stay untouched.
+    fn new(inner: R, total: Option<u64>) -> Self {
+        let total = total
+            .filter(|size| *size >= PROGRESS_MIN_SIZE)
+            .filter(|_| env::var_os(QUIET_VAR).is_none() && io::stderr().is_terminal())
+            .unwrap_or(0);
+        Self {
+            inner,
+            done: 0,
+            total,
+            shown: None,
+        }
+    }
+}
+
+impl<R: Read> Read for Progress<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.done += n as u64;
+        if self.total > 0
+            && self
+                .shown
+                .is_none_or(|at| at.elapsed() >= PROGRESS_INTERVAL)
+        {
+            eprint!(
+                "\r[rex] Extracting {} MB ... {}%",
+                self.total >> 20,
+                (self.done * 100 / self.total).min(100)
+            );
+            self.shown = Some(Instant::now());
+        }
+        Ok(n)
+    }
+}
+
+impl<R: Read> Drop for Progress<R> {
+    fn drop(&mut self) {
+        if self.shown.is_some() {
+            eprint!("\r\x1b[K");
+            let _ = io::stderr().flush();
+        }
+    }
+}
+
+struct ChildEnv {
+    set: Vec<(String, String)>,
+    removed: Vec<String>,
+}
+
+impl ChildEnv {
+    fn apply(&self, command: &mut Command) {
+        for key in &self.removed {
+            command.env_remove(key);
+        }
+        command.envs(self.set.iter().map(|(k, v)| (k, v)));
+    }
 }
 
 pub struct Runtime {
     payload_info: Option<PayloadInfo>,
+    info: Option<BundleInfo>,
     executed: bool,
+    exit_code: Option<i32>,
 }
 
 impl Runtime {
-    pub fn new() -> Result<Self, Box<dyn Error>> {
-        let payload_info = Self::find_payload_info()?;
+    pub fn new() -> Result<Self> {
+        Self::ensure_linux()?;
+        let exec = Self::self_exe()?;
+        let payload_info = Self::find_payload_info(&exec, bundle::launcher_is_stamped())?;
+        if payload_info.is_none() && bundle::launcher_is_stamped() {
+            return Err(RexError::BundleCorrupt(format!(
+                "{} was built as a bundle but its payload is gone; post-processing such as \
+                 `strip` drops appended data. Rebuild it, or use --embed-mode section to \
+                 keep the payload across such tools",
+                env::current_exe().unwrap_or(exec).display()
+            )));
+        }
         Ok(Self {
+            info: payload_info.as_ref().map(BundleInfo::from),
             payload_info,
             executed: false,
+            exit_code: None,
         })
     }
 
+    /// Fails early on non-Linux hosts, where neither the bundled loaders nor
+    /// the procfs-based self-detection can work.
+    pub fn ensure_linux() -> Result<()> {
+        if cfg!(target_os = "linux") {
+            Ok(())
+        } else {
+            Err(RexError::UnsupportedHost(env::consts::OS))
+        }
+    }
+
+    /// Path to read this executable's own bytes from. `/proc/self/exe` always
+    /// refers to the running image, whatever symlinks it was invoked through,
+    /// while `current_exe()` may be resolved from argv[0] on some systems;
+    /// the latter is only used when procfs is not mounted.
+    pub fn self_exe() -> io::Result<PathBuf> {
+        let proc_exe = Path::new(PROC_SELF_EXE);
+        match File::open(proc_exe) {
+            Ok(_) => Ok(proc_exe.to_path_buf()),
+            Err(_) => env::current_exe(),
+        }
+    }
+
     pub fn is_bundled(&self) -> bool {
-        self.payload_info.is_some()
+        self.info.is_some()
+    }
+
+    /// Parsed trailer of this executable, or `None` when it is not a bundle.
+    /// Stays available after `run`.
+    pub fn info(&self) -> Option<&BundleInfo> {
+        self.info.as_ref()
     }
 
+    /// Whether the bundled program ran and exited on its own, so a failure
+    /// was already reported by its output and `exit_code`. Errors from rex
+    /// itself (extraction, missing loader, timeout, ...) leave this false.
     pub fn has_run(&self) -> bool {
         self.executed
     }
 
-    pub fn run(&mut self) -> Result<(), Box<dyn Error>> {
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        if let (Some(cmd), Some(info)) = (env::args().nth(1), &self.payload_info) {
+            match cmd.as_str() {
+                "--rex-info" => {
+                    if let Some(bundle) = self.info() {
+                        Self::print_info(bundle);
+                    }
+                    return Ok(());
+                }
+                "--rex-env" => return Self::print_env(info),
+                "--rex-doctor" => return Self::doctor(info),
+                cmd if info.options.install_dir.is_some()
+                    && cmd == info.options.install_command.as_deref().unwrap_or("install") =>
+                {
+                    return Self::install(info);
+                }
+                "--rex-extract-debug" if info.options.debug_info => {
+                    let dest = env::args().nth(2).ok_or_else(|| {
+                        RexError::Usage("Usage: --rex-extract-debug <DIR>".into())
+                    })?;
+                    let count = Self::extract_subtree(info, "debug", Path::new(&dest))?;
+                    println!("[rex] Extracted {count} debug files to {dest}");
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+
         #[cfg(debug_assertions)]
         {
             let args: Vec<String> = env::args().collect();
-            if args.len() > 1 && args[1] == "--rex-extract" {
-                if let Some(info) = &self.payload_info {
-                    let current_dir = env::current_dir()?;
-                    println!("[rex] Extracting bundle to {}", current_dir.display());
-                    Self::extract_payload(info, &current_dir)?;
-                    println!("[rex] Extraction completed successfully!");
-                    return Ok(());
+            if let (Some(cmd), Some(info)) = (args.get(1), &self.payload_info) {
+                match cmd.as_str() {
+                    "--rex-extract" if args.get(2).is_some_and(|a| a == "--as-tar") => {
+                        let dest = Path::new(args.get(3).ok_or_else(|| {
+                            RexError::Usage("Usage: --rex-extract --as-tar <FILE>".into())
+                        })?);
+                        println!("[rex] Copying payload to {}", dest.display());
+                        let mut file = File::open(&info.source)?;
+                        file.seek(SeekFrom::Start(info.payload_start_offset))?;
+                        let copied = io::copy(
+                            &mut file.take(info.metadata.payload_size),
+                            &mut File::create(dest)?,
+                        )?;
+                        println!("[rex] Wrote {copied} bytes of .tar.zstd payload");
+                        return Ok(());
+                    }
+                    "--rex-extract" => {
+                        let current_dir = env::current_dir()?;
+                        println!("[rex] Extracting bundle to {}", current_dir.display());
+                        Self::extract_payload(info, &current_dir)?;
+                        println!("[rex] Extraction completed successfully!");
+                        return Ok(());
+                    }
+                    "--rex-extract-libs" => {
+                        let dest = Path::new(args.get(2).ok_or_else(|| {
+                            RexError::Usage("Usage: --rex-extract-libs <DIR>".into())
+                        })?);
+                        println!("[rex] Extracting libs to {}", dest.display());
+                        let count = Self::extract_subtree(info, "libs", dest)?;
+                        println!("[rex] Extracted {count} libs successfully!");
+                        return Ok(());
+                    }
+                    "--rex-verify" => {
+                        Self::verify_payload(info)?;
+                        println!("[rex] Bundle verified successfully!");
+                        return Ok(());
+                    }
+                    _ => {}
                 }
             }
         }
@@ -64,118 +310,1285 @@ impl Runtime {
             .map_or(Ok(()), |info| self.run_bundled_binary(&info))
     }
 
-    fn find_payload_info() -> Result<Option<PayloadInfo>, Box<dyn Error>> {
-        let exec = env::current_exe()?;
-        let mut file = File::open(&exec)?;
+    // `--installer-mode`: extract into the baked directory (or the one given
+    // after the install command) instead of running the target.
+    fn install(info: &PayloadInfo) -> Result<()> {
+        let dest = match env::args().nth(2) {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(info.options.install_dir.as_deref().unwrap_or_default()),
+        };
+        println!(
+            "[rex] Installing {} to {}",
+            info.target_binary_name,
+            dest.display()
+        );
+        fs::create_dir_all(&dest)?;
+        Self::extract_payload(info, &dest)?;
+        println!(
+            "[rex] Installed to {}",
+            dest.join(format!("{}_bundle", info.target_binary_name))
+                .display()
+        );
+        Ok(())
+    }
+
+    fn print_info(info: &BundleInfo) {
+        println!("[rex] Source: {}", info.source.display());
+        println!("[rex] Target: {}", info.target_name);
+        println!(
+            "[rex] Payload: {} bytes at offset {}",
+            info.payload_size, info.payload_offset
+        );
+        match (info.options.codec, info.options.level) {
+            (Some(codec), Some(level)) => {
+                println!("[rex] Compression: {} level {level}", codec.name())
+            }
+            (Some(codec), None) => println!("[rex] Compression: {}", codec.name()),
+            // Bundles from before the codec was recorded are always zstd.
+            (None, _) => println!("[rex] Compression: zstd (level not recorded)"),
+        }
+        for record in info.options.encode().split(|b| *b == 0) {
+            if !record.is_empty() {
+                println!("[rex] Option: {}", String::from_utf8_lossy(record));
+            }
+        }
+    }
+
+    // `--rex-doctor`: runs every launch precondition against a scratch
+    // extraction and reports each one, with what to do about failures.
+    fn doctor(info: &PayloadInfo) -> Result<()> {
+        let name = &info.target_binary_name;
+        println!("[rex] Diagnosing '{name}' ({})", info.source.display());
+        let mut failed = 0;
+        let mut report = |check: &str, result: std::result::Result<String, String>| match result {
+            Ok(detail) => println!("[rex]   PASS  {check}: {detail}"),
+            Err(advice) => {
+                failed += 1;
+                println!("[rex]   FAIL  {check}: {advice}");
+            }
+        };
+
+        let tmp = env::temp_dir();
+        report(
+            "Extraction directory",
+            match fsutil::is_noexec(&tmp) {
+                Ok(false) => Ok(tmp.display().to_string()),
+                Ok(true) => Err(format!(
+                    "{} is mounted noexec, so nothing extracted there can run; \
+                     point TMPDIR at another directory",
+                    tmp.display()
+                )),
+                Err(e) => Err(format!("cannot inspect {}: {e}", tmp.display())),
+            },
+        );
+
+        if info.options.min_glibc.is_some() {
+            report(
+                "Host glibc",
+                Self::check_host_glibc(info)
+                    .map(|_| Self::host_glibc_version().unwrap_or_default())
+                    .map_err(|e| e.to_string()),
+            );
+        }
+
+        let scratch = tmp.join(format!("{name}_doctor_{}", std::process::id()));
+        fs::create_dir_all(&scratch)?;
+        let extracted = Self::extract_payload(info, &scratch);
+        let integrity = info.options.integrity.map_or("fnv64", |mode| mode.name());
+        report(
+            "Payload checksum",
+            match &extracted {
+                Ok(()) => Ok(format!("{integrity} matches")),
+                Err(e) => Err(format!("{e}; the file is damaged, fetch or build it again")),
+            },
+        );
+
+        if extracted.is_ok() {
+            let bundle_dir = scratch.join(format!("{name}_bundle"));
+            let libs_dir = bundle_dir.join("libs");
+            let target = bundle_dir.join(name);
+            report(
+                "Target",
+                if target.is_file() {
+                    Ok(format!("{name} present"))
+                } else {
+                    Err(format!(
+                        "{name} is missing from the payload; rebuild the bundle"
+                    ))
+                },
+            );
+
+            match Self::find_loader(&libs_dir) {
+                Ok(loader) => {
+                    let loader_name = loader.file_name().unwrap_or_default().to_string_lossy();
+                    let arch = Elf::open(&loader).ok().and_then(|elf| elf.arch());
+                    let host = env::consts::ARCH;
+                    report(
+                        "Loader",
+                        match arch {
+                            Some(arch) if arch == host => Ok(format!("{loader_name} ({arch})")),
+                            Some(arch) => Err(format!(
+                                "{loader_name} is for {arch} but this host is {host}; \
+                                 rebuild on {host} or run with {QEMU_VAR}=1"
+                            )),
+                            None => Err(format!("cannot tell the architecture of {loader_name}")),
+                        },
+                    );
+                    if arch == Some(host) && target.is_file() {
+                        report(
+                            "Libraries",
+                            Self::preload_check(
+                                &loader,
+                                &target,
+                                &Self::library_path(&libs_dir),
+                                &Self::child_env(info, &bundle_dir),
+                            )
+                            .map(|_| "all resolve inside the bundle".to_string())
+                            .map_err(|e| format!("{e}; rebuild with the missing libraries (-l)")),
+                        );
+                    }
+                }
+                Err(e) => report(
+                    "Loader",
+                    Err(format!("{e}; rebuild the bundle so the loader is staged")),
+                ),
+            }
+        }
+        let _ = fsutil::remove_dir_all(&scratch);
+
+        if failed > 0 {
+            return Err(RexError::ChecksFailed(failed));
+        }
+        println!("[rex] All checks passed");
+        Ok(())
+    }
+
+    pub fn verify_bundle(path: &Path) -> Result<()> {
+        let info = Self::find_payload_info(path, true)?
+            .ok_or_else(|| RexError::BundleCorrupt("no bundle payload found".into()))?;
+        Self::verify_payload(&info)
+    }
+
+    /// Extracts the bundle at `path` into `dest`, returning the target name and
+    /// the baked options so the payload can be rebuilt around them.
+    pub fn unpack_bundle(path: &Path, dest: &Path) -> Result<(String, BundleOptions)> {
+        let info = Self::find_payload_info(path, true)?
+            .ok_or_else(|| RexError::BundleCorrupt("no bundle payload found".into()))?;
+        Self::extract_payload(&info, dest)?;
+        Ok((info.target_binary_name, info.options))
+    }
+
+    fn verify_payload(info: &PayloadInfo) -> Result<()> {
+        let scratch = env::temp_dir().join(format!(
+            "{}_verify_{}",
+            info.target_binary_name,
+            std::process::id()
+        ));
+        fs::create_dir_all(&scratch)?;
+
+        let result = Self::extract_payload(info, &scratch).and_then(|_| {
+            let bundle_dir = scratch.join(format!("{}_bundle", info.target_binary_name));
+            if !bundle_dir.join(&info.target_binary_name).is_file() {
+                return Err(RexError::BundleCorrupt(format!(
+                    "target '{}' missing from payload",
+                    info.target_binary_name
+                )));
+            }
+            Self::find_loader(&bundle_dir.join("libs")).map(|_| ())
+        });
+
+        let _ = fs::remove_dir_all(&scratch);
+        result
+    }
+
+    pub fn find_loader(libs_dir: &Path) -> Result<PathBuf> {
+        fs::read_dir(libs_dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|e| e.path())
+            .find(|p| elf::is_loader(p))
+            .ok_or(RexError::LoaderNotFound)
+    }
+
+    // The common cases cost one small read: a bundle ends in the marker, and
+    // the plain CLI does not. Only with `diagnose` (for the launcher itself,
+    // when it was stamped as a bundle) is a file without a trailing marker
+    // looked at further, for a section payload or a displaced trailer.
+    fn find_payload_info(exec: &Path, diagnose: bool) -> Result<Option<PayloadInfo>> {
+        let mut file = File::open(exec)?;
         let file_size = file.metadata()?.len();
 
-        const FIXED_METADATA_SIZE: u64 =
-            size_of::<BundleMetadata>() as u64 + MAGIC_MARKER.len() as u64;
+        let mut tail = [0u8; MAGIC_MARKER.len()];
+        if let Some(marker_pos) = file_size.checked_sub(MAGIC_MARKER.len() as u64) {
+            file.seek(SeekFrom::Start(marker_pos))?;
+            file.read_exact(&mut tail)?;
+            if tail == MAGIC_MARKER {
+                return Self::read_trailer(&mut file, exec, marker_pos)
+                    .map(Some)
+                    .map_err(|e| Self::corrupt_trailer(exec, e));
+            }
+        }
+        if !diagnose {
+            return Ok(None);
+        }
 
-        let start_pos = file_size.saturating_sub(FIXED_METADATA_SIZE + 256);
-        file.seek(SeekFrom::Start(start_pos))?;
+        if let Some(marker_pos) = Self::section_marker(&mut file, exec)? {
+            return Self::read_trailer(&mut file, exec, marker_pos)
+                .map(Some)
+                .map_err(|e| Self::corrupt_trailer(exec, e));
+        }
 
+        // Only a file ending in the marker is a bundle. A marker elsewhere in
+        // the tail means the trailer was truncated or data was appended
+        // after it, which must not fall through to the generator CLI.
+        const FIXED_METADATA_SIZE: u64 =
+            size_of::<BundleMetadata>() as u64 + 1 + MAGIC_MARKER.len() as u64;
+        file.seek(SeekFrom::Start(
+            file_size.saturating_sub(FIXED_METADATA_SIZE + 256),
+        ))?;
         let mut buffer = Vec::new();
         file.read_to_end(&mut buffer)?;
-
         let marker_idx = buffer
             .windows(MAGIC_MARKER.len())
             .rposition(|w| w == MAGIC_MARKER);
-        let marker_pos = match marker_idx {
-            Some(idx) => start_pos + idx as u64,
-            None => return Ok(None),
+        match marker_idx {
+            Some(idx) => Err(RexError::BundleCorrupt(format!(
+                "{} has {} bytes of unexpected data after its trailer",
+                exec.display(),
+                buffer.len() - idx - MAGIC_MARKER.len()
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    fn corrupt_trailer(exec: &Path, e: RexError) -> RexError {
+        match e {
+            RexError::BundleCorrupt(_) => e,
+            e => RexError::BundleCorrupt(format!("unreadable trailer in {}: {e}", exec.display())),
+        }
+    }
+
+    // `--embed-mode section` bundles keep payload and trailer in an ELF
+    // section, so the marker ends the section rather than the file.
+    fn section_marker(file: &mut File, exec: &Path) -> Result<Option<u64>> {
+        let Some((offset, size)) = elf::find_section(exec, elf::PAYLOAD_SECTION).ok().flatten()
+        else {
+            return Ok(None);
+        };
+        let Some(marker_pos) = (offset + size).checked_sub(MAGIC_MARKER.len() as u64) else {
+            return Ok(None);
         };
+        let mut marker = [0u8; MAGIC_MARKER.len()];
+        file.seek(SeekFrom::Start(marker_pos))?;
+        if file.read_exact(&mut marker).is_err() || marker != MAGIC_MARKER {
+            return Ok(None);
+        }
+        Ok(Some(marker_pos))
+    }
 
-        let meta_pos = marker_pos
-            .checked_sub(size_of::<BundleMetadata>() as u64)
-            .ok_or("Invalid metadata")?;
-        file.seek(SeekFrom::Start(meta_pos))?;
-        let mut meta_bytes = [0u8; size_of::<BundleMetadata>()];
-        file.read_exact(&mut meta_bytes)?;
+    fn read_trailer(file: &mut File, exec: &Path, marker_pos: u64) -> Result<PayloadInfo> {
+        let invalid = || RexError::BundleCorrupt("invalid metadata".into());
+        let version_pos = marker_pos.checked_sub(1).ok_or_else(invalid)?;
+        let mut version = [0u8];
+        file.seek(SeekFrom::Start(version_pos))?;
+        file.read_exact(&mut version)?;
+        let (metadata, meta_pos) = match version[0] {
+            0 => {
+                let meta_pos = marker_pos
+                    .checked_sub(LEGACY_METADATA_SIZE as u64)
+                    .ok_or_else(invalid)?;
+                let mut meta_bytes = [0u8; LEGACY_METADATA_SIZE];
+                file.seek(SeekFrom::Start(meta_pos))?;
+                file.read_exact(&mut meta_bytes)?;
+                (BundleMetadata::from_legacy_bytes(&meta_bytes), meta_pos)
+            }
+            FORMAT_VERSION => {
+                let meta_pos = version_pos
+                    .checked_sub(size_of::<BundleMetadata>() as u64)
+                    .ok_or_else(invalid)?;
+                let mut meta_bytes = [0u8; size_of::<BundleMetadata>()];
+                file.seek(SeekFrom::Start(meta_pos))?;
+                file.read_exact(&mut meta_bytes)?;
+                (BundleMetadata::from_bytes(&meta_bytes), meta_pos)
+            }
+            version => {
+                return Err(RexError::BundleCorrupt(format!(
+                    "{} uses trailer format {version}, newer than this rex ({FORMAT_VERSION})",
+                    exec.display()
+                )));
+            }
+        };
+        let payload_size = metadata.payload_size;
+        let name_len = metadata.target_bin_name_len as u64;
+        let options_len = metadata.options_len as u64;
 
-        let payload_size = u64::from_le_bytes(meta_bytes[0..8].try_into().unwrap());
-        let name_len = u32::from_le_bytes(meta_bytes[8..12].try_into().unwrap()) as u64;
+        let options_pos = meta_pos
+            .checked_sub(options_len)
+            .ok_or_else(|| RexError::BundleCorrupt("invalid options offset".into()))?;
+        file.seek(SeekFrom::Start(options_pos))?;
+        let mut options_bytes = vec![0u8; options_len as usize];
+        file.read_exact(&mut options_bytes)?;
+        let options = BundleOptions::unpack(&options_bytes)?;
 
-        let name_pos = meta_pos
+        let name_pos = options_pos
             .checked_sub(name_len)
-            .ok_or("Invalid name offset")?;
+            .ok_or_else(|| RexError::BundleCorrupt("invalid name offset".into()))?;
         file.seek(SeekFrom::Start(name_pos))?;
         let mut name_bytes = vec![0u8; name_len as usize];
         file.read_exact(&mut name_bytes)?;
-        let target_binary_name = String::from_utf8(name_bytes)?;
+        let target_binary_name = String::from_utf8(name_bytes)
+            .map_err(|_| RexError::BundleCorrupt("invalid target name".into()))?;
 
         let payload_start_offset = name_pos
-            .checked_sub(payload_size)
-            .ok_or("Invalid payload offset")?;
+            .checked_sub(payload_size + options.store_size.unwrap_or_default())
+            .ok_or_else(|| RexError::BundleCorrupt("invalid payload offset".into()))?;
 
-        Ok(Some(PayloadInfo {
-            metadata: BundleMetadata {
-                payload_size,
-                target_bin_name_len: name_len as u32,
-            },
+        Ok(PayloadInfo {
+            source: exec.to_path_buf(),
+            metadata,
             payload_start_offset,
             target_binary_name,
-        }))
+            options,
+        })
     }
 
-    fn extract_payload(info: &PayloadInfo, dest_path: &Path) -> Result<(), Box<dyn Error>> {
-        let exec = env::current_exe()?;
-        let mut file = File::open(&exec)?;
+    // The payload hash is computed while the archives are unpacked, so
+    // verification costs no extra pass over the file. On a mismatch (or any
+    // other failure) the partially extracted tree is removed.
+    fn extract_payload(info: &PayloadInfo, dest_path: &Path) -> Result<()> {
+        let bundle_dir = dest_path.join(format!("{}_bundle", info.target_binary_name));
+        let result = Self::unpack_verified(info, dest_path)
+            .and_then(|_| Self::restore_blobs(info, &bundle_dir));
+        if result.is_err() {
+            let _ = fsutil::remove_dir_all(&bundle_dir);
+        }
+        result?;
+        Self::restore_capabilities(info, &bundle_dir);
+        Ok(())
+    }
+
+    // Libraries of `--dedup-across-bundles` bundles are copied back from the
+    // shared store (`REX_DEDUP_STORE` overrides the baked path) and checked
+    // against their hash, since the store is outside the verified payload.
+    fn restore_blobs(info: &PayloadInfo, bundle_dir: &Path) -> Result<()> {
+        if info.options.blobs.is_empty() {
+            return Ok(());
+        }
+        let store = env::var_os(DEDUP_STORE_VAR)
+            .map(PathBuf::from)
+            .or_else(|| info.options.dedup_store.as_ref().map(PathBuf::from))
+            .ok_or_else(|| RexError::BundleCorrupt("no dedup store recorded".into()))?;
+        for (path, hash) in &info.options.blobs {
+            let dest = bundle_dir.join(path);
+            fs::copy(store.join(hash), &dest).map_err(|e| {
+                RexError::Launch(format!(
+                    "Cannot get {path} from dedup store {}: {e}",
+                    store.display()
+                ))
+            })?;
+            let mut digest = Digest::new(Integrity::Sha256);
+            digest.update_reader(File::open(&dest)?)?;
+            if digest.finish_hex() != *hash {
+                return Err(RexError::BundleCorrupt(format!(
+                    "{hash} in dedup store {} does not match its hash",
+                    store.display()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // Setting file capabilities needs CAP_SETFCAP, so this is best effort:
+    // without it the tool runs, but may hit permission errors.
+    fn restore_capabilities(info: &PayloadInfo, bundle_dir: &Path) {
+        for (path, value) in &info.options.capabilities {
+            if let Err(e) = fsutil::set_xattr(&bundle_dir.join(path), CAPABILITY_XATTR, value) {
+                eprintln!(
+                    "[rex] Could not restore file capabilities of {path} ({e}); \
+                     it may fail with permission errors"
+                );
+            }
+        }
+    }
+
+    fn unpack_verified(info: &PayloadInfo, dest_path: &Path) -> Result<()> {
+        let mut file = File::open(&info.source)?;
         file.seek(SeekFrom::Start(info.payload_start_offset))?;
+        let mut hasher = match info.options.integrity {
+            Some(mode) => Digest::new(mode),
+            None => Digest::Fnv(Fnv64::new()),
+        };
+
+        let mut payload_reader = (&mut file).take(info.metadata.payload_size);
+        {
+            let decoder = zstd::Decoder::new(HashingReader::new(&mut payload_reader, &mut hasher))?;
+            let decoder = Progress::new(decoder, info.options.unpacked_size);
+            archive::unpack_all(decoder, dest_path)
+                .map_err(|e| RexError::Archive(e.to_string()))?;
+        }
+        io::copy(
+            &mut HashingReader::new(&mut payload_reader, &mut hasher),
+            &mut io::sink(),
+        )?;
 
-        let payload_reader = file.take(info.metadata.payload_size);
-        let decoder = zstd::Decoder::new(payload_reader)?;
-        let mut archive = tar_minimal::Decoder::new(decoder);
-        archive.unpack(&dest_path.display().to_string())?;
+        if let Some(store_size) = info.options.store_size {
+            let store_offset = info.payload_start_offset + info.metadata.payload_size;
+            file.seek(SeekFrom::Start(store_offset))?;
+            let mut store_reader = (&mut file).take(store_size);
+            archive::unpack_all(
+                HashingReader::new(&mut store_reader, &mut hasher),
+                dest_path,
+            )
+            .map_err(|e| RexError::Archive(e.to_string()))?;
+            io::copy(
+                &mut HashingReader::new(&mut store_reader, &mut hasher),
+                &mut io::sink(),
+            )?;
+        }
+
+        let expected = match info.options.integrity {
+            Some(_) => info.options.digest.clone(),
+            None => info.options.payload_hash.map(|hash| format!("{hash:016x}")),
+        };
+        let actual = hasher.finish_hex();
+        if let Some(expected) = expected
+            && actual != expected
+        {
+            return Err(RexError::BundleCorrupt(format!(
+                "payload checksum mismatch (expected {expected}, got {actual})"
+            )));
+        }
         Ok(())
     }
 
-    fn run_bundled_binary(&mut self, info: &PayloadInfo) -> Result<(), Box<dyn Error>> {
-        let extraction_root = env::temp_dir();
-        Self::extract_payload(info, extraction_root.as_path())?;
+    // Hardlinks to a file whose first name lies outside `subdir` are
+    // resolved by a second pass that copies that file in.
+    fn extract_subtree(info: &PayloadInfo, subdir: &str, dest: &Path) -> Result<usize> {
+        let open = || -> Result<_> {
+            let mut file = File::open(&info.source)?;
+            file.seek(SeekFrom::Start(info.payload_start_offset))?;
+            let decoder = zstd::Decoder::new(file.take(info.metadata.payload_size))?;
+            Ok(archive::Reader::new(decoder))
+        };
+        let mut reader = open()?;
 
-        let bundle_dir = extraction_root.join(format!("{}_bundle", info.target_binary_name));
-        let bin_dir = bundle_dir.join("bins");
-        let libs_dir = bundle_dir.join("libs");
-        let target_bin_path = bundle_dir.join(&info.target_binary_name);
+        let prefix = format!("{}_bundle/{subdir}/", info.target_binary_name);
+        let mut count = 0;
+        let mut outside: Vec<(String, String)> = vec![];
+        while let Some(mut entry) = reader.next_entry()? {
+            let Some(rel) = entry.path.strip_prefix(&prefix).map(str::to_string) else {
+                continue;
+            };
+            entry.path = rel;
+            if entry.kind == EntryKind::Hardlink {
+                match entry.link.strip_prefix(&prefix) {
+                    Some(link) => entry.link = link.to_string(),
+                    None => {
+                        archive::check_entry(&entry, dest)?;
+                        outside.push((entry.link, entry.path));
+                        count += 1;
+                        continue;
+                    }
+                }
+            }
+            archive::check_entry(&entry, dest)?;
+            reader.unpack(&entry, &dest.join(&entry.path), dest)?;
+            if entry.kind != EntryKind::Dir {
+                count += 1;
+            }
+        }
 
-        let loader = fs::read_dir(&libs_dir)?
-            .filter_map(|entry| entry.ok())
-            .map(|e| e.path())
-            .find(|p| {
-                let name = p.file_name().and_then(|n| n.to_str()).unwrap_or("");
-                name.starts_with("ld-linux") || name.starts_with("ld-musl")
-            })
-            .ok_or("No compatible loader found")?;
+        if !outside.is_empty() {
+            let mut reader = open()?;
+            while let Some(mut entry) = reader.next_entry()? {
+                let names: Vec<&String> = outside
+                    .iter()
+                    .filter(|(link, _)| *link == entry.path)
+                    .map(|(_, name)| name)
+                    .collect();
+                let Some((first, rest)) = names.split_first() else {
+                    continue;
+                };
+                if entry.kind != EntryKind::File {
+                    continue;
+                }
+                entry.path = first.to_string();
+                reader.unpack(&entry, &dest.join(first), dest)?;
+                for name in rest {
+                    let _ = fs::remove_file(dest.join(name));
+                    fs::hard_link(dest.join(first), dest.join(name))?;
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    fn cache_root() -> PathBuf {
+        env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".cache")))
+            .unwrap_or_else(env::temp_dir)
+            .join("rex")
+    }
 
+    // Extracts into a private `<hash>.tmp.<pid>` directory and publishes it
+    // with an atomic rename, so concurrent launches only ever see a complete
+    // tree. When another launch wins the rename, its copy is used instead.
+    fn extract_cached(info: &PayloadInfo) -> Result<PathBuf> {
+        let hash = info
+            .options
+            .payload_hash
+            .ok_or_else(|| RexError::BundleCorrupt("missing payload hash".into()))?;
+        let root = Self::cache_root();
+        let name = format!("{}_{:08x}", info.target_binary_name, hash >> 32);
+        let published = root.join(&name);
+
+        // The lock only spares concurrent first launches from extracting the
+        // same payload several times; the rename alone keeps them correct.
+        // It is removed once done: waiters then retry on a fresh lock file
+        // and find the tree published.
+        fs::create_dir_all(&root)?;
+        let lock = fsutil::FileLock::exclusive(&root.join(format!("{name}.lock")))?;
+        let result = Self::publish_cached(info, &root, &name);
+        let _ = lock.remove();
+        result?;
+
+        Ok(published.join(format!("{}_bundle", info.target_binary_name)))
+    }
+
+    fn publish_cached(info: &PayloadInfo, root: &Path, name: &str) -> Result<()> {
+        let published = root.join(name);
+        if published.exists() {
+            return Ok(());
+        }
+        let tmp = root.join(format!("{name}.tmp.{}", std::process::id()));
+        let _ = fs::remove_dir_all(&tmp);
+        fs::create_dir_all(&tmp)?;
+
+        if let Err(e) = Self::extract_payload(info, &tmp) {
+            let _ = fs::remove_dir_all(&tmp);
+            return Err(e);
+        }
+        if fs::rename(&tmp, &published).is_err() {
+            let _ = fs::remove_dir_all(&tmp);
+            if !published.exists() {
+                return Err(RexError::CachePublish(published));
+            }
+        }
+        Ok(())
+    }
+
+    fn cleanup(info: &PayloadInfo, extraction: &Extraction) {
+        if extraction.keep {
+            return;
+        }
+        if let Some(lock) = &extraction.lock
+            && !lock.try_exclusive()
+        {
+            return;
+        }
+        if info.options.secure_cleanup
+            && let Err(e) = fsutil::zero_files(&extraction.root)
+        {
+            eprintln!("[rex] Secure cleanup incomplete: {e}");
+        }
+        let _ = fsutil::remove_dir_all(&extraction.root);
+        if let Some(lock) = &extraction.lock {
+            let _ = lock.remove();
+        }
+    }
+
+    // Launches of the same bundle share `$TMPDIR/<target>_<hash8>`, extracted
+    // once under an exclusive lock and removed by the last launch to exit.
+    // REX_NO_CACHE=1 forces a private extraction removed on exit instead,
+    // also for --concurrency-safe-cache bundles. Bundles without a hash, and
+    // relinked direct-exec ones whose baked PT_INTERP lies in this TMPDIR's
+    // fixed `<target>_bundle`, share that directory the same way.
+    fn extract_for_run(info: &PayloadInfo) -> Result<Extraction> {
+        let name = &info.target_binary_name;
+        let no_cache = env::var(NO_CACHE_VAR).as_deref() == Ok("1");
+        if info.options.cache && !no_cache {
+            return Ok(Extraction {
+                dir: Self::extract_cached(info)?,
+                root: PathBuf::new(),
+                lock: None,
+                keep: true,
+            });
+        }
+
+        let fixed = env::temp_dir().join(format!("{name}_bundle"));
+        let direct = info.options.relinked
+            && info.options.no_loader_wrapper
+            && info
+                .options
+                .interpreter
+                .as_ref()
+                .and_then(|interp| Path::new(interp).parent())
+                .is_some_and(|libs| libs == fixed.join("libs"));
+        let hash = info.options.payload_hash.filter(|_| !direct);
+        let Some(hash) = hash else {
+            let lock_path = env::temp_dir().join(format!("{name}_bundle.lock"));
+            return Self::extract_shared(info, &env::temp_dir(), fixed, &lock_path);
+        };
+
+        let base = format!("{name}_{:08x}", hash >> 32);
+        let shared = env::temp_dir().join(&base);
+        if no_cache {
+            let root = env::temp_dir().join(format!("{base}.{}", std::process::id()));
+            Self::extract_payload(info, &root)?;
+            return Ok(Extraction {
+                dir: root.join(format!("{name}_bundle")),
+                root,
+                lock: None,
+                keep: false,
+            });
+        }
+
+        let lock_path = env::temp_dir().join(format!("{base}.lock"));
+        Self::extract_shared(info, &shared, shared.clone(), &lock_path)
+    }
+
+    // Extracts into `parent` unless `root` (`parent` or the `<target>_bundle`
+    // in it) is already complete, holding `lock_path` exclusively while doing
+    // so and shared while running. The last launch to leave removes the lock
+    // file along with the tree.
+    fn extract_shared(
+        info: &PayloadInfo,
+        parent: &Path,
+        root: PathBuf,
+        lock_path: &Path,
+    ) -> Result<Extraction> {
+        loop {
+            let lock = fsutil::FileLock::exclusive(lock_path)?;
+            let marker = root.join(COMPLETE_MARKER);
+            if !marker.exists() {
+                let _ = fsutil::remove_dir_all(&root);
+                let extracted = Self::extract_payload(info, parent)
+                    .and_then(|_| File::create(&marker).map_err(RexError::from));
+                if let Err(e) = extracted {
+                    let _ = lock.remove();
+                    return Err(e);
+                }
+            }
+            lock.downgrade()?;
+            // Another launch may have taken the lock during the downgrade
+            // and removed the tree (and lock file) on its way out; extract
+            // it again then.
+            if marker.exists() && lock.is_current() {
+                return Ok(Extraction {
+                    dir: parent.join(format!("{}_bundle", info.target_binary_name)),
+                    root,
+                    lock: Some(lock),
+                    keep: false,
+                });
+            }
+        }
+    }
+
+    // Changes applied to the inherited environment of the hook and the
+    // target: bins/ on PATH and, in clean environment mode, stripped vars.
+    fn child_env(info: &PayloadInfo, bundle_dir: &Path) -> ChildEnv {
+        let mut set = vec![];
+        let bin_dir = bundle_dir.join("bins");
         if bin_dir.exists() {
             let existing = env::var("PATH").unwrap_or_default();
-            let new_path = format!("{}:{}", bin_dir.display(), existing);
-            unsafe {
-                env::set_var("PATH", new_path);
+            let new_path = if info.options.path_append {
+                format!("{existing}:{}", bin_dir.display())
+            } else {
+                format!("{}:{existing}", bin_dir.display())
+            };
+            set.push(("PATH".to_string(), new_path));
+        }
+        let removed = if Self::clean_env_enabled(&info.options) {
+            Self::stripped_env_vars(&info.options)
+        } else {
+            vec![]
+        };
+        ChildEnv { set, removed }
+    }
+
+    fn print_env(info: &PayloadInfo) -> Result<()> {
+        let extraction = Self::extract_for_run(info)?;
+        let child_env = Self::child_env(info, &extraction.dir);
+        Self::cleanup(info, &extraction);
+
+        let mut vars: Vec<(String, String)> = env::vars_os()
+            .map(|(k, v)| {
+                (
+                    k.to_string_lossy().into_owned(),
+                    v.to_string_lossy().into_owned(),
+                )
+            })
+            .filter(|(k, _)| !child_env.removed.contains(k))
+            .filter(|(k, _)| !child_env.set.iter().any(|(s, _)| s == k))
+            .chain(child_env.set.iter().cloned())
+            .collect();
+        vars.sort();
+        for (key, value) in vars {
+            println!("{key}={value}");
+        }
+        Ok(())
+    }
+
+    fn run_bundled_binary(&mut self, info: &PayloadInfo) -> Result<()> {
+        Self::check_host_glibc(info)?;
+        let (tool, skip) = Self::select_tool(info)?;
+        let qemu = Self::find_qemu(&info.options)?;
+        let extraction = Self::extract_for_run(info)?;
+        let bundle_dir = extraction.dir.clone();
+        let libs_dir = bundle_dir.join("libs");
+        let target_bin_path = if tool == info.target_binary_name {
+            bundle_dir.join(&tool)
+        } else {
+            bundle_dir.join("bins").join(&tool)
+        };
+
+        let loader = match Self::find_loader(&libs_dir) {
+            Ok(loader) => loader,
+            Err(e) => {
+                Self::cleanup(info, &extraction);
+                return Err(e);
             }
+        };
+        let child_env = Self::child_env(info, &bundle_dir);
+
+        if let Some(hook) = &info.options.post_extract
+            && let Err(e) = self.run_hook(hook, &bundle_dir, &child_env)
+        {
+            Self::cleanup(info, &extraction);
+            return Err(e);
         }
 
-        let args: Vec<String> = env::args().skip(1).collect();
-        let mut cmd_args = vec![
-            "--library-path".to_string(),
-            libs_dir.to_string_lossy().into(),
-            target_bin_path.to_string_lossy().into(),
-        ];
+        // Debug builds only: leading `--rex-run-with=<arg>` entries are handed
+        // to the loader (e.g. `--list`) instead of the target.
+        let mut args = env::args().skip(1 + skip).peekable();
+        let mut run_with = vec![];
+        while cfg!(debug_assertions)
+            && let Some(arg) = args.next_if(|a| a.starts_with(RUN_WITH_PREFIX))
+        {
+            run_with.push(arg[RUN_WITH_PREFIX.len()..].to_string());
+        }
+        let library_path = Self::library_path(&libs_dir);
+        let mut cmd_args = vec!["--library-path".to_string(), library_path.clone()];
+        cmd_args.extend(info.options.loader_args.iter().cloned());
+        if Self::loader_supports_argv0(&loader) {
+            cmd_args.extend(["--argv0".to_string(), tool.clone()]);
+        }
+        cmd_args.extend(run_with.iter().cloned());
+        cmd_args.push(target_bin_path.to_string_lossy().into());
+        let target_args = cmd_args.len();
+        cmd_args.extend(info.options.prepend_args.iter().cloned());
         cmd_args.extend(args);
 
-        let result = Command::new(loader)
-            .args(&cmd_args)
+        let open_stdin = || -> io::Result<Stdio> {
+            Ok(match &info.options.stdin_file {
+                Some(name) => Stdio::from(File::open(bundle_dir.join(name))?),
+                None => Stdio::inherit(),
+            })
+        };
+        let stdin = match open_stdin() {
+            Ok(stdin) => stdin,
+            Err(e) => {
+                Self::cleanup(info, &extraction);
+                return Err(RexError::Launch(format!(
+                    "Cannot open the bundled stdin file: {e}"
+                )));
+            }
+        };
+
+        if qemu.is_none()
+            && env::var(PRELOAD_CHECK_VAR).as_deref() == Ok("1")
+            && let Err(e) =
+                Self::preload_check(&loader, &target_bin_path, &library_path, &child_env)
+        {
+            Self::cleanup(info, &extraction);
+            return Err(e);
+        }
+        let script = info
+            .options
+            .entrypoint_script
+            .as_ref()
+            .map(|name| bundle_dir.join(name));
+        // Relinked bundles point PT_INTERP at the loader in the default
+        // extraction directory; anywhere else the loader wrapper is needed.
+        let direct = qemu.is_none()
+            && script.is_none()
+            && run_with.is_empty()
+            && info.options.no_loader_wrapper
+            && Elf::open(&target_bin_path)
+                .ok()
+                .and_then(|elf| elf.interpreter())
+                .is_some_and(|interp| Path::new(&interp) == loader);
+        let mut command = if let Some(script) = &script {
+            // The script gets what it needs to exec the target itself:
+            // `exec "$REX_LOADER" --library-path "$REX_LIBRARY_PATH" "$REX_TARGET" "$@"`.
+            let bundled_sh = bundle_dir.join("bins/sh");
+            let mut command = Command::new(if bundled_sh.exists() {
+                bundled_sh.as_path()
+            } else {
+                Path::new("/bin/sh")
+            });
+            command
+                .arg(script)
+                .env("REX_BUNDLE_DIR", &bundle_dir)
+                .env("REX_TARGET", &target_bin_path)
+                .env("REX_LOADER", &loader)
+                .env("REX_LIBRARY_PATH", &library_path);
+            command
+        } else if let Some(qemu) = &qemu {
+            let mut command = Command::new(qemu);
+            command.arg("-L").arg(&libs_dir).arg(&loader);
+            command
+        } else if direct {
+            let mut command = Command::new(&target_bin_path);
+            command.arg0(&tool);
+            command
+        } else {
+            Command::new(&loader)
+        };
+        let launch_args = if direct || script.is_some() {
+            &cmd_args[target_args..]
+        } else {
+            &cmd_args[..]
+        };
+        child_env.apply(&mut command);
+        let spawned = command
+            .args(launch_args)
             .current_dir(&bundle_dir)
-            .status();
+            .stdin(stdin)
+            .spawn();
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(e) => {
+                Self::cleanup(info, &extraction);
+                return Err(RexError::Launch(format!(
+                    "Failed to launch {}: {e}",
+                    Path::new(command.get_program()).display()
+                )));
+            }
+        };
+
+        let name = &tool;
+        let pidfile = env::var_os(PIDFILE_VAR)
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| info.options.pidfile.as_ref().map(PathBuf::from));
+        Self::write_pidfile(pidfile.as_deref(), &child);
+        let mut result = Self::wait_child(&mut child, info.options.timeout);
 
-        self.executed = true;
-        let _ = fs::remove_dir_all(&bundle_dir);
+        // REX_FALLBACK_HOST_LOADER=1: when the bundled loader itself fails,
+        // run the target once more through its own PT_INTERP, i.e. the host
+        // loader with the host libraries.
+        if !direct
+            && qemu.is_none()
+            && script.is_none()
+            && env::var(FALLBACK_HOST_LOADER_VAR).as_deref() == Ok("1")
+            && let Ok(Some(status)) = &result
+            && Self::is_loader_failure(status)
+        {
+            eprintln!(
+                "[rex] Warning: the bundled loader failed to run '{name}'; \
+                 retrying with the host loader and libraries"
+            );
+            let mut command = Command::new(&target_bin_path);
+            command.arg0(name);
+            child_env.apply(&mut command);
+            let retried = open_stdin().and_then(|stdin| {
+                command
+                    .args(&cmd_args[target_args..])
+                    .current_dir(&bundle_dir)
+                    .stdin(stdin)
+                    .spawn()
+            });
+            match retried {
+                Ok(mut child) => {
+                    Self::write_pidfile(pidfile.as_deref(), &child);
+                    result = Self::wait_child(&mut child, info.options.timeout);
+                }
+                Err(e) => eprintln!("[rex] Could not start '{name}' with the host loader: {e}"),
+            }
+        }
+        if let Some(path) = &pidfile {
+            let _ = fs::remove_file(path);
+        }
+        // Exit 127 is also "command not found" in shells and `env`, so the
+        // loader is asked whether a library is really missing before the
+        // bundle gets the blame.
+        let missing = match &result {
+            Ok(Some(s))
+                if s.code() == Some(LOADER_FAILURE_EXIT_CODE)
+                    && qemu.is_none()
+                    && script.is_none() =>
+            {
+                Self::unresolved_libs(&loader, &target_bin_path, &library_path, &child_env)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .filter(|(_, outside)| outside.is_none())
+                    .map(|(name, _)| name)
+                    .collect()
+            }
+            _ => vec![],
+        };
+        Self::cleanup(info, &extraction);
 
+        // Only an exit of its own makes the target's output the last word;
+        // timeouts and wait failures are reported by rex like any other error.
+        if let Ok(Some(_)) = result {
+            self.executed = true;
+        }
         match result {
-            Ok(s) if s.success() => Ok(()),
-            Ok(_) => Err("fail".into()),
-            Err(e) => Err(format!("Failed to execute: {e}").into()),
+            Ok(Some(s)) if s.success() => Ok(()),
+            Ok(Some(s)) => {
+                let code = s.code().or(s.signal().map(|sig| 128 + sig));
+                self.exit_code = code;
+                if !missing.is_empty() {
+                    eprintln!(
+                        "[rex] The bundled loader could not start '{name}': error while \
+                         loading shared libraries: {}. Rebundle with --strict-deps to \
+                         catch missing libraries at build time",
+                        missing.join(", ")
+                    );
+                }
+                Err(RexError::ProgramFailed {
+                    name: name.clone(),
+                    code: code.unwrap_or(1),
+                })
+            }
+            Ok(None) => {
+                self.exit_code = Some(TIMEOUT_EXIT_CODE);
+                Err(RexError::Timeout(info.options.timeout.unwrap_or_default()))
+            }
+            Err(e) => Err(RexError::Launch(format!("Lost track of '{name}': {e}"))),
+        }
+    }
+
+    // Which program of a `--toolbox` bundle to run, and how many launch
+    // arguments that choice used up: a symlink named after a tool, else a
+    // first argument naming one, else (on a terminal) a numbered menu.
+    fn select_tool(info: &PayloadInfo) -> Result<(String, usize)> {
+        let tools = &info.options.tools;
+        if tools.is_empty() {
+            return Ok((info.target_binary_name.clone(), 0));
+        }
+        let argv0 = env::args().next().unwrap_or_default();
+        let invoked = Path::new(&argv0).file_name().unwrap_or_default();
+        let bundle_name = env::current_exe().ok();
+        let bundle_name = bundle_name.as_deref().and_then(Path::file_name);
+        if Some(invoked) != bundle_name
+            && let Some(tool) = tools.iter().find(|t| invoked == t.as_str())
+        {
+            return Ok((tool.clone(), 0));
+        }
+        match env::args().nth(1) {
+            Some(arg) => match tools.iter().find(|t| **t == arg) {
+                Some(tool) => Ok((tool.clone(), 1)),
+                None => Err(RexError::Usage(format!(
+                    "Unknown tool '{arg}'; this bundle provides: {}",
+                    tools.join(", ")
+                ))),
+            },
+            None if io::stdin().is_terminal() && io::stderr().is_terminal() => {
+                eprintln!("[rex] Tools in this bundle:");
+                for (i, tool) in tools.iter().enumerate() {
+                    eprintln!("  {}) {tool}", i + 1);
+                }
+                eprint!("Select a tool [1-{}]: ", tools.len());
+                let mut answer = String::new();
+                io::stdin().read_line(&mut answer)?;
+                let answer = answer.trim();
+                answer
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|n| tools.get(n.checked_sub(1)?))
+                    .or_else(|| tools.iter().find(|t| *t == answer))
+                    .map(|tool| (tool.clone(), 0))
+                    .ok_or_else(|| RexError::Usage(format!("No such tool: '{answer}'")))
+            }
+            None => Err(RexError::Usage(format!(
+                "Pass the tool to run as the first argument: {}",
+                tools.join(", ")
+            ))),
+        }
+    }
+
+    // The loader runs the target in its own process (and qemu-user emulates
+    // it in place), so the child's PID is the program's.
+    fn write_pidfile(path: Option<&Path>, child: &Child) {
+        if let Some(path) = path
+            && let Err(e) = fs::write(path, format!("{}\n", child.id()))
+        {
+            eprintln!("[rex] Could not write pid file {}: {e}", path.display());
+        }
+    }
+
+    // Exit 127 (the loader could not load a library) or a crash signal. A
+    // target crashing with the same signals cannot be told apart.
+    fn is_loader_failure(status: &ExitStatus) -> bool {
+        status.code() == Some(LOADER_FAILURE_EXIT_CODE)
+            || status
+                .signal()
+                .is_some_and(|sig| LOADER_CRASH_SIGNALS.contains(&sig))
+    }
+
+    // REX_PRELOAD_CHECK=1: has the loader resolve the target before running it and fails on every library that is missing or was
+    // only found outside the library path, i.e. outside the bundle.
+    fn preload_check(
+        loader: &Path,
+        target: &Path,
+        library_path: &str,
+        child_env: &ChildEnv,
+    ) -> Result<()> {
+        let unresolved: Vec<String> =
+            Self::unresolved_libs(loader, target, library_path, child_env)?
+                .into_iter()
+                .map(|(name, outside)| match outside {
+                    Some(path) => format!("{name} (found outside the bundle: {path})"),
+                    None => name,
+                })
+                .collect();
+        if !unresolved.is_empty() {
+            return Err(RexError::Launch(format!(
+                "{PRELOAD_CHECK_VAR}: {} has unresolved libraries: {}",
+                target.file_name().unwrap_or_default().to_string_lossy(),
+                unresolved.join(", ")
+            )));
+        }
+        Ok(())
+    }
+
+    // The libraries of `target` that the loader reports missing, or resolves
+    // outside the library path (with the path it found). Traced the way `ldd`
+    // does it: unlike `--list`, which stops at the first missing library,
+    // tracing lists them all as `not found`.
+    fn unresolved_libs(
+        loader: &Path,
+        target: &Path,
+        library_path: &str,
+        child_env: &ChildEnv,
+    ) -> Result<Vec<(String, Option<String>)>> {
+        let mut command = Command::new(loader);
+        child_env.apply(&mut command);
+        let output = command
+            .env("LD_TRACE_LOADED_OBJECTS", "1")
+            .arg("--library-path")
+            .arg(library_path)
+            .arg(target)
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| RexError::Launch(format!("Failed to trace {}: {e}", loader.display())))?;
+
+        let dirs: Vec<PathBuf> = env::split_paths(library_path).collect();
+        let mut unresolved = vec![];
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            let Some((name, resolved)) = line.trim().split_once(" => ") else {
+                continue;
+            };
+            let path = resolved.split_whitespace().next().unwrap_or_default();
+            if resolved.starts_with("not found") {
+                unresolved.push((name.to_string(), None));
+            } else if !dirs.iter().any(|dir| Path::new(path).starts_with(dir)) {
+                unresolved.push((name.to_string(), Some(path.to_string())));
+            }
+        }
+        if unresolved.is_empty() && !output.status.success() {
+            return Err(RexError::Launch(format!(
+                "Tracing libraries with {} failed: {}",
+                loader.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
         }
+        Ok(unresolved)
+    }
+
+    // Runs the baked post-extract hook through `sh -c` inside the extraction
+    // directory. Its failure is reported on its own and its exit code is
+    // propagated, so it is not mistaken for a failure of the target.
+    fn run_hook(&mut self, hook: &str, bundle_dir: &Path, child_env: &ChildEnv) -> Result<()> {
+        let mut command = Command::new("sh");
+        child_env.apply(&mut command);
+        let status = command
+            .arg("-c")
+            .arg(hook)
+            .current_dir(bundle_dir)
+            .env("REX_BUNDLE_DIR", bundle_dir)
+            .status()
+            .map_err(|e| {
+                RexError::Launch(format!("Failed to run post-extract hook '{hook}': {e}"))
+            })?;
+        if status.success() {
+            return Ok(());
+        }
+        let code = status
+            .code()
+            .or(status.signal().map(|sig| 128 + sig))
+            .unwrap_or(1);
+        self.exit_code = Some(code);
+        Err(RexError::HookFailed {
+            hook: hook.to_string(),
+            code,
+        })
+    }
+
+    // With REX_QEMU=1, a bundle built for another architecture runs under the
+    // matching qemu-user emulator instead of failing to exec.
+    fn find_qemu(options: &BundleOptions) -> Result<Option<PathBuf>> {
+        let Some(arch) = options.arch.as_deref() else {
+            return Ok(None);
+        };
+        if arch == env::consts::ARCH || env::var(QEMU_VAR).as_deref() != Ok("1") {
+            return Ok(None);
+        }
+        let name = match arch {
+            "x86" => "qemu-i386".to_string(),
+            "powerpc" => "qemu-ppc".to_string(),
+            "powerpc64" => "qemu-ppc64le".to_string(),
+            other => format!("qemu-{other}"),
+        };
+        fsutil::which(&name)
+            .or_else(|| fsutil::which(&format!("{name}-static")))
+            .map(Some)
+            .ok_or_else(|| {
+                RexError::Launch(format!(
+                    "{QEMU_VAR}=1 but {name} was not found in PATH for this {arch} bundle"
+                ))
+            })
+    }
+
+    // `--argv0` makes the program see its own name instead of the extracted
+    // path, which multi-call tools rely on. musl's loader has it, glibc's
+    // only since 2.33 and older ones reject unknown options.
+    fn loader_supports_argv0(loader: &Path) -> bool {
+        let name = loader.file_name().unwrap_or_default().to_string_lossy();
+        if name.starts_with("ld-musl") {
+            return true;
+        }
+        let Ok(Some(version)) = elf::glibc_version(loader) else {
+            return false;
+        };
+        elf::parse_glibc_version(&version).is_some_and(|v| v >= ARGV0_GLIBC)
+    }
+
+    /// glibc version of the host, `None` on musl and other non-glibc hosts.
+    pub fn host_glibc_version() -> Option<String> {
+        #[cfg(target_env = "gnu")]
+        {
+            unsafe extern "C" {
+                fn gnu_get_libc_version() -> *const std::ffi::c_char;
+            }
+            let version = unsafe { std::ffi::CStr::from_ptr(gnu_get_libc_version()) };
+            Some(version.to_string_lossy().into_owned())
+        }
+        // A launcher not linked against glibc reads the host's libc.so.6.
+        #[cfg(not(target_env = "gnu"))]
+        {
+            let triplet = format!("{}-linux-gnu", env::consts::ARCH);
+            ["/lib", "/usr/lib"]
+                .iter()
+                .map(|dir| Path::new(dir).join(&triplet))
+                .chain(HOST_LIB_DIRS.iter().map(PathBuf::from))
+                .find_map(|dir| elf::glibc_version(&dir.join("libc.so.6")).ok().flatten())
+        }
+    }
+
+    // `--target-glibc`: refuse to start on a host whose glibc is older than
+    // the one the bundle was built to rely on.
+    fn check_host_glibc(info: &PayloadInfo) -> Result<()> {
+        let Some(min) = &info.options.min_glibc else {
+            return Ok(());
+        };
+        let name = &info.target_binary_name;
+        let host = Self::host_glibc_version();
+        match host.as_deref().and_then(elf::parse_glibc_version) {
+            Some(version) if Some(version) >= elf::parse_glibc_version(min) => Ok(()),
+            Some(_) => Err(RexError::Launch(format!(
+                "'{name}' needs glibc {min} or newer, but this host has glibc {}",
+                host.unwrap_or_default()
+            ))),
+            None => Err(RexError::Launch(format!(
+                "'{name}' needs glibc {min} or newer, but no glibc was found on this host"
+            ))),
+        }
+    }
+
+    // REX_EXTRA_LIBPATH directories come right after libs/, so bundled libs
+    // still win. With REX_LIB_FALLBACK=1 the host's standard library
+    // directories are searched last, so a lib missing from the bundle
+    // resolves there.
+    fn library_path(libs_dir: &Path) -> String {
+        let mut dirs = vec![libs_dir.to_path_buf()];
+        if let Some(extra) = env::var_os(EXTRA_LIBPATH_VAR) {
+            for dir in env::split_paths(&extra).filter(|d| !d.as_os_str().is_empty()) {
+                if dir.is_dir() {
+                    dirs.push(dir);
+                } else {
+                    eprintln!(
+                        "[rex] Ignoring {EXTRA_LIBPATH_VAR} entry {}: not a directory",
+                        dir.display()
+                    );
+                }
+            }
+        }
+        if env::var(LIB_FALLBACK_VAR).as_deref() == Ok("1") {
+            let triplet = format!("{}-linux-gnu", env::consts::ARCH);
+            dirs.extend(["/lib", "/usr/lib"].map(|dir| Path::new(dir).join(&triplet)));
+            dirs.extend(HOST_LIB_DIRS.iter().map(PathBuf::from));
+            dirs.retain(|dir| dir.is_dir());
+        }
+        env::join_paths(dirs)
+            .map(|path| path.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| libs_dir.to_string_lossy().into_owned())
+    }
+
+    // REX_CLEAN_ENV=1/0 at launch time overrides what was baked at build time.
+    fn clean_env_enabled(options: &BundleOptions) -> bool {
+        match env::var(CLEAN_ENV_VAR) {
+            Ok(v) => v == "1",
+            Err(_) => options.clean_env,
+        }
+    }
+
+    fn stripped_env_vars(options: &BundleOptions) -> Vec<String> {
+        let prefixes: Vec<&str> = if options.clean_env_prefixes.is_empty() {
+            DEFAULT_CLEAN_PREFIXES.to_vec()
+        } else {
+            options
+                .clean_env_prefixes
+                .iter()
+                .map(String::as_str)
+                .collect()
+        };
+        env::vars_os()
+            .filter_map(|(k, _)| k.into_string().ok())
+            .filter(|k| prefixes.iter().any(|p| k.starts_with(p)))
+            .filter(|k| !options.keep_env.contains(k))
+            .collect()
+    }
+
+    fn wait_child(child: &mut Child, timeout: Option<u64>) -> io::Result<Option<ExitStatus>> {
+        let Some(secs) = timeout else {
+            return child.wait().map(Some);
+        };
+
+        let deadline = Instant::now() + Duration::from_secs(secs);
+        while Instant::now() < deadline {
+            if let Some(status) = child.try_wait()? {
+                return Ok(Some(status));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        unsafe {
+            kill(child.id() as i32, SIGTERM);
+        }
+        let grace = Instant::now() + KILL_GRACE;
+        while Instant::now() < grace {
+            if child.try_wait()?.is_some() {
+                return Ok(None);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        child.kill()?;
+        child.wait()?;
+        Ok(None)
     }
 }
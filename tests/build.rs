@@ -0,0 +1,26 @@
+mod common;
+
+use common::{Sandbox, stderr, stdout};
+
+#[test]
+fn strict_deps_fails_on_a_missing_dependency() {
+    let sandbox = Sandbox::new("strict-missing");
+    sandbox.write_broken_sh("broken-sh");
+
+    let output = sandbox
+        .command(env!("CARGO_BIN_EXE_Rex"))
+        .env("TMPDIR", sandbox.path("build-tmp"))
+        .args(["-t", "broken-sh", "--strict-deps", "-o", "broken.Rex"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(stdout(&output).contains("[Warning] Unresolved dependency: libq.so.6"));
+    let message = stderr(&output);
+    assert!(
+        message.contains("unresolved dependency: libq.so.6 (needed by"),
+        "{message}"
+    );
+    assert!(!sandbox.path("broken.Rex").exists());
+
+    sandbox.build(&["-q", "-t", "broken-sh", "-o", "lenient.Rex"]);
+}
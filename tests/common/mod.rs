@@ -0,0 +1,104 @@
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// A scratch directory for one test, with its own TMPDIR and cache so
+/// extractions of concurrent tests never meet (builds get a separate TMPDIR,
+/// keeping their staging out of `tmp_entries`). Removed on drop.
+pub struct Sandbox {
+    pub dir: PathBuf,
+}
+
+impl Sandbox {
+    pub fn new(name: &str) -> Self {
+        let dir = env::temp_dir().join(format!("rex-test-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("tmp")).unwrap();
+        fs::create_dir_all(dir.join("build-tmp")).unwrap();
+        fs::create_dir_all(dir.join("cache")).unwrap();
+        Self { dir }
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    pub fn command(&self, program: impl AsRef<Path>) -> Command {
+        let mut command = Command::new(program.as_ref());
+        command
+            .current_dir(&self.dir)
+            .env("TMPDIR", self.dir.join("tmp"))
+            .env("XDG_CACHE_HOME", self.dir.join("cache"))
+            .env("REX_QUIET", "1")
+            .env_remove("RUST_LOG");
+        command
+    }
+
+    /// Runs the generator with `args`, failing the test unless it succeeds.
+    pub fn build(&self, args: &[&str]) -> Output {
+        let output = self
+            .command(env!("CARGO_BIN_EXE_Rex"))
+            .env("TMPDIR", self.dir.join("build-tmp"))
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "rex {args:?} failed:\n{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        output
+    }
+
+    /// Writes a copy of `/bin/sh` to `name` whose libc dependency is renamed
+    /// to `libq.so.6`, a library no host has.
+    pub fn write_broken_sh(&self, name: &str) {
+        let mut sh = fs::read("/bin/sh").unwrap();
+        let needle = b"libc.so.6\0";
+        for at in 0..sh.len() - needle.len() {
+            if &sh[at..at + needle.len()] == needle {
+                sh[at + 3] = b'q';
+            }
+        }
+        fs::write(self.path(name), sh).unwrap();
+        fs::set_permissions(self.path(name), fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    /// Entries left in the sandbox TMPDIR, i.e. leaked extractions or locks.
+    pub fn tmp_entries(&self) -> Vec<String> {
+        self.entries("tmp")
+    }
+
+    /// Entries of the sandbox's `rex` cache directory.
+    pub fn cache_entries(&self) -> Vec<String> {
+        self.entries("cache/rex")
+    }
+
+    fn entries(&self, dir: &str) -> Vec<String> {
+        let mut entries: Vec<String> = fs::read_dir(self.dir.join(dir))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        entries.sort();
+        entries
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+pub fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+pub fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
@@ -0,0 +1,79 @@
+mod common;
+
+use common::{Sandbox, stderr, stdout};
+use std::fs;
+
+fn info(sandbox: &Sandbox, bundle: &str) -> String {
+    let output = sandbox
+        .command(sandbox.path(bundle))
+        .arg("--rex-info")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    stdout(&output)
+}
+
+#[test]
+fn edits_keep_the_embed_mode_unless_given_again() {
+    let sandbox = Sandbox::new("edit-embed-mode");
+    fs::write(sandbox.path("input.txt"), "appended\n").unwrap();
+    sandbox.build(&["-q", "-t", "/bin/cat", "--embed-mode", "section", "-o", "cat.Rex"]);
+    assert!(info(&sandbox, "cat.Rex").contains("[rex] Option: embed_mode=section\n"));
+
+    sandbox.build(&["-q", "--append-to", "cat.Rex", "-f", "input.txt"]);
+    assert!(info(&sandbox, "cat.Rex").contains("[rex] Option: embed_mode=section\n"));
+    let output = sandbox
+        .command(sandbox.path("cat.Rex"))
+        .arg(sandbox.path("input.txt"))
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "appended\n");
+
+    let args = ["-q", "--append-to", "cat.Rex", "-f", "input.txt"];
+    sandbox.build(&[&args[..], &["--embed-mode", "append"]].concat());
+    assert!(info(&sandbox, "cat.Rex").contains("[rex] Option: embed_mode=append\n"));
+}
+
+/// Rewrites a fresh bundle of `/bin/cat` with the original trailer: name,
+/// payload size and name length, with no options or format version.
+fn write_legacy_bundle(sandbox: &Sandbox, name: &str) {
+    let output = sandbox.build(&["-q", "-t", "/bin/cat", "-o", name]);
+    let summary = stdout(&output);
+    let size = |label: &str| -> usize {
+        let line = summary.lines().find_map(|l| l.trim().strip_prefix(label)).unwrap();
+        line.trim().trim_end_matches(" bytes").parse().unwrap()
+    };
+    let end = size("Launcher Size:") + size("Payload Size:");
+    let mut bundle = fs::read(sandbox.path(name)).unwrap();
+    bundle.truncate(end);
+    bundle.extend_from_slice(b"cat");
+    bundle.extend_from_slice(&(size("Payload Size:") as u64).to_le_bytes());
+    bundle.extend_from_slice(&3u32.to_le_bytes());
+    bundle.extend_from_slice(b"REX_BUNDLE");
+    fs::write(sandbox.path(name), bundle).unwrap();
+}
+
+#[test]
+fn bundles_with_the_legacy_trailer_still_run_and_edit() {
+    let sandbox = Sandbox::new("edit-legacy-trailer");
+    fs::write(sandbox.path("input.txt"), "legacy\n").unwrap();
+    write_legacy_bundle(&sandbox, "cat.Rex");
+    let run = |sandbox: &Sandbox| {
+        let output = sandbox
+            .command(sandbox.path("cat.Rex"))
+            .arg(sandbox.path("input.txt"))
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", stderr(&output));
+        assert_eq!(stdout(&output), "legacy\n");
+    };
+    run(&sandbox);
+    assert!(info(&sandbox, "cat.Rex").contains("[rex] Target: cat\n"));
+
+    sandbox.build(&["-q", "--append-to", "cat.Rex", "-f", "input.txt"]);
+    run(&sandbox);
+    assert!(info(&sandbox, "cat.Rex").contains("[rex] Option: embed_mode=append\n"));
+    let bundle = fs::read(sandbox.path("cat.Rex")).unwrap();
+    assert_eq!(bundle[bundle.len() - 11], 2, "format version before the marker");
+}
@@ -0,0 +1,297 @@
+mod common;
+
+use common::{Sandbox, stderr, stdout};
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{PermissionsExt, symlink};
+use std::path::Path;
+use std::process::Stdio;
+
+#[test]
+fn pipes_stdin_through_the_target() {
+    let sandbox = Sandbox::new("stdin-pipe");
+    sandbox.build(&["-q", "-t", "/bin/cat", "-o", "cat.Rex"]);
+
+    let mut child = sandbox
+        .command(sandbox.path("cat.Rex"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let input = "first line\nsecond line\n".repeat(1000);
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), input);
+    assert_eq!(sandbox.tmp_entries(), Vec::<String>::new());
+}
+
+#[test]
+fn feeds_the_baked_stdin_file() {
+    let sandbox = Sandbox::new("stdin-file");
+    fs::write(sandbox.path("input.txt"), "baked input\n").unwrap();
+    sandbox.build(&[
+        "-q",
+        "-t",
+        "/bin/cat",
+        "-f",
+        "input.txt",
+        "--stdin-file",
+        "input.txt",
+        "-o",
+        "cat.Rex",
+    ]);
+
+    let output = sandbox
+        .command(sandbox.path("cat.Rex"))
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "baked input\n");
+}
+
+#[test]
+fn target_sees_its_own_name_as_argv0() {
+    let sandbox = Sandbox::new("argv0");
+    sandbox.build(&["-q", "-t", "/bin/sh", "-o", "sh.Rex"]);
+    sandbox.build(&["-q", "-t", "/bin/sh", "--target-name", "tool", "-o", "tool.Rex"]);
+
+    for (bundle, name) in [("sh.Rex", "sh"), ("tool.Rex", "tool")] {
+        let output = sandbox
+            .command(sandbox.path(bundle))
+            .args(["-c", r#"echo "$0""#])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(stdout(&output), format!("{name}\n"));
+    }
+}
+
+#[test]
+fn child_exit_codes_pass_through_without_a_rex_message() {
+    let sandbox = Sandbox::new("child-exit");
+    sandbox.build(&["-q", "-t", "/bin/sh", "-o", "sh.Rex"]);
+
+    let output = sandbox
+        .command(sandbox.path("sh.Rex"))
+        .args(["-c", "echo out; exit 3"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(stdout(&output), "out\n");
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn extraction_failures_are_reported() {
+    let sandbox = Sandbox::new("extract-fail");
+    let build = stdout(&sandbox.build(&["-q", "-t", "/bin/sh", "-o", "sh.Rex"]));
+    let launcher: usize = build
+        .lines()
+        .find_map(|l| l.trim().strip_prefix("Launcher Size: "))
+        .and_then(|l| l.strip_suffix(" bytes"))
+        .unwrap()
+        .parse()
+        .unwrap();
+
+    let mut bundle = fs::read(sandbox.path("sh.Rex")).unwrap();
+    for byte in &mut bundle[launcher + 4096..launcher + 4160] {
+        *byte ^= 0xff;
+    }
+    fs::write(sandbox.path("corrupt.Rex"), bundle).unwrap();
+    fs::set_permissions(sandbox.path("corrupt.Rex"), fs::Permissions::from_mode(0o755)).unwrap();
+    fs::write(sandbox.path("not-a-dir"), "").unwrap();
+
+    let corrupt = sandbox
+        .command(sandbox.path("corrupt.Rex"))
+        .args(["-c", "exit 0"])
+        .output()
+        .unwrap();
+    let unwritable = sandbox
+        .command(sandbox.path("sh.Rex"))
+        .env("TMPDIR", sandbox.path("not-a-dir"))
+        .args(["-c", "exit 0"])
+        .output()
+        .unwrap();
+    for output in [corrupt, unwritable] {
+        assert_eq!(output.status.code(), Some(1));
+        assert_eq!(stdout(&output), "");
+        assert!(!stderr(&output).is_empty());
+    }
+}
+
+/// Starts `count` instances of `bundle` at once and checks each one ran.
+fn run_concurrently(sandbox: &Sandbox, bundle: &str, count: usize) {
+    let children: Vec<_> = (0..count)
+        .map(|i| {
+            sandbox
+                .command(sandbox.path(bundle))
+                .args(["-c", &format!("echo {i}")])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    for (i, child) in children.into_iter().enumerate() {
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success(), "instance {i}: {}", stderr(&output));
+        assert_eq!(stdout(&output), format!("{i}\n"));
+    }
+}
+
+#[test]
+fn concurrent_launches_share_one_published_cache() {
+    let sandbox = Sandbox::new("concurrent-cache");
+    sandbox.build(&["-q", "-t", "/bin/sh", "--concurrency-safe-cache", "-o", "sh.Rex"]);
+
+    run_concurrently(&sandbox, "sh.Rex", 16);
+    let cache = sandbox.cache_entries();
+    assert_eq!(cache.len(), 1, "{cache:?}");
+    assert!(!cache[0].contains(".tmp.") && !cache[0].ends_with(".lock"), "{cache:?}");
+    assert_eq!(sandbox.tmp_entries(), Vec::<String>::new());
+
+    run_concurrently(&sandbox, "sh.Rex", 16);
+    assert_eq!(sandbox.cache_entries(), cache);
+}
+
+#[test]
+fn concurrent_launches_extract_and_clean_up_independently() {
+    let sandbox = Sandbox::new("concurrent-tmp");
+    sandbox.build(&["-q", "-t", "/bin/sh", "-o", "sh.Rex"]);
+
+    run_concurrently(&sandbox, "sh.Rex", 16);
+    assert_eq!(sandbox.tmp_entries(), Vec::<String>::new());
+}
+
+#[test]
+fn symlinked_targets_are_bundled_under_the_chosen_name() {
+    let sandbox = Sandbox::new("symlink-target");
+    symlink("/bin/sh", sandbox.path("mysh")).unwrap();
+    sandbox.build(&["-q", "-t", "mysh"]);
+    sandbox.build(&["-q", "-t", "mysh", "--resolve-target-name"]);
+
+    let real = fs::canonicalize("/bin/sh").unwrap();
+    let real = real.file_name().unwrap().to_str().unwrap();
+    for name in ["mysh", real] {
+        let output = sandbox
+            .command(sandbox.path(&format!("{name}.Rex")))
+            .args(["-c", r#"echo "$0""#])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", stderr(&output));
+        assert_eq!(stdout(&output), format!("{name}\n"));
+    }
+}
+
+#[test]
+fn bundles_run_through_a_symlink() {
+    let sandbox = Sandbox::new("symlink-bundle");
+    sandbox.build(&["-q", "-t", "/bin/cat", "-o", "cat.Rex"]);
+    fs::write(sandbox.path("input.txt"), "through a link\n").unwrap();
+    fs::create_dir(sandbox.path("bin")).unwrap();
+    symlink("cat.Rex", sandbox.path("link")).unwrap();
+    symlink("../cat.Rex", sandbox.path("bin/on-path")).unwrap();
+
+    let path = format!("{}:{}", sandbox.path("bin").display(), env::var("PATH").unwrap_or_default());
+    for mut command in [
+        sandbox.command(sandbox.path("link")),
+        sandbox.command("on-path"),
+    ] {
+        let output = command.env("PATH", &path).arg(sandbox.path("input.txt")).output().unwrap();
+        assert!(output.status.success(), "{}", stderr(&output));
+        assert_eq!(stdout(&output), "through a link\n");
+    }
+}
+
+#[test]
+fn symlinked_data_files_are_kept_as_links_unless_dereferenced() {
+    let sandbox = Sandbox::new("symlink-data");
+    fs::write(sandbox.path("v2"), "version 2\n").unwrap();
+    symlink("v2", sandbox.path("current")).unwrap();
+    let common = ["-q", "-t", "/bin/sh", "-f", "v2", "-f", "current"];
+    sandbox.build(&[&common[..], &["-o", "kept.Rex"]].concat());
+    sandbox.build(&[&common[..], &["--dereference-data", "-o", "deref.Rex"]].concat());
+
+    let probe = "if [ -L current ]; then echo link; else echo file; fi; cat current";
+    for (bundle, kind) in [("kept.Rex", "link"), ("deref.Rex", "file")] {
+        let output = sandbox
+            .command(sandbox.path(bundle))
+            .args(["-c", probe])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", stderr(&output));
+        assert_eq!(stdout(&output), format!("{kind}\nversion 2\n"));
+    }
+}
+
+#[test]
+fn scripts_run_through_the_bundled_interpreter() {
+    let sandbox = Sandbox::new("follow-interpreter");
+    fs::write(sandbox.path("tool.sh"), "#!/bin/sh\nreadlink /proc/$$/exe\necho \"args: $*\"\n").unwrap();
+    fs::set_permissions(sandbox.path("tool.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+    let common = ["-q", "-t", "/bin/sh", "-f", "tool.sh", "--follow-interpreter"];
+    sandbox.build(&[&common[..], &["-o", "prepend.Rex"]].concat());
+    sandbox.build(&[&common[..], &["--no-path-prepend", "-o", "append.Rex"]].concat());
+
+    let tmp = fs::canonicalize(sandbox.path("tmp")).unwrap();
+    for bundle in ["prepend.Rex", "append.Rex"] {
+        let output = sandbox
+            .command(sandbox.path(bundle))
+            .args(["-c", "./tool.sh a b"])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{bundle}: {}", stderr(&output));
+        let stdout = stdout(&output);
+        let (exe, rest) = stdout.split_once('\n').unwrap();
+        assert!(
+            Path::new(exe).starts_with(&tmp) && exe.contains("_bundle/libs/"),
+            "{bundle}: interpreter ran as {exe}"
+        );
+        assert_eq!(rest, "args: a b\n");
+    }
+}
+
+/// The `PT_INTERP` of a 64-bit little-endian ELF file.
+fn interpreter(elf: &[u8]) -> String {
+    let u16_at = |at: usize| u16::from_le_bytes(elf[at..at + 2].try_into().unwrap()) as usize;
+    let u64_at = |at: usize| u64::from_le_bytes(elf[at..at + 8].try_into().unwrap()) as usize;
+    let (phoff, phentsize, phnum) = (u64_at(0x20), u16_at(0x36), u16_at(0x38));
+    let phdr = (0..phnum)
+        .map(|i| phoff + i * phentsize)
+        .find(|at| elf[*at..*at + 4] == 3u32.to_le_bytes())
+        .unwrap();
+    let (offset, size) = (u64_at(phdr + 8), u64_at(phdr + 0x20));
+    String::from_utf8(elf[offset..offset + size - 1].to_vec()).unwrap()
+}
+
+#[test]
+fn missing_libraries_are_diagnosed_but_exit_127_alone_is_not() {
+    let sandbox = Sandbox::new("missing-lib");
+    sandbox.write_broken_sh("broken-sh");
+    let loader = interpreter(&fs::read("/bin/sh").unwrap());
+    sandbox.build(&["-q", "-t", "broken-sh", "-l", &loader, "-o", "broken.Rex"]);
+    sandbox.build(&["-q", "-t", "/bin/sh", "-o", "sh.Rex"]);
+
+    let output = sandbox.command(sandbox.path("broken.Rex")).output().unwrap();
+    assert_eq!(output.status.code(), Some(127));
+    let message = stderr(&output);
+    assert!(message.contains("error while loading shared libraries"), "{message}");
+    assert!(message.contains("[rex] The bundled loader could not start 'broken-sh'"), "{message}");
+    assert!(message.contains("libq.so.6") && message.contains("--strict-deps"), "{message}");
+
+    let output = sandbox
+        .command(sandbox.path("sh.Rex"))
+        .args(["-c", "exit 127"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(127));
+    assert_eq!(stderr(&output), "");
+}